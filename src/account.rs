@@ -0,0 +1,77 @@
+use crate::api;
+use crate::crypto::SecretKey;
+use crate::persona::Persona;
+use std::error::Error;
+
+/// Prompt for the app password, log in and save the session.
+pub async fn login(
+    persona: &Persona,
+    session_key: Option<&SecretKey>,
+) -> Result<(), Box<dyn Error>> {
+    let password =
+        rpassword::prompt_password(format!("app password for {}: ", persona.identifier))?;
+    let agent = api::login(
+        &persona.service,
+        &persona.identifier,
+        password.trim(),
+        &persona.session_options(session_key),
+    )
+    .await?;
+    let session = agent
+        .get_session()
+        .await
+        .ok_or("no session after logging in")?;
+    println!(
+        "logged in as {} ({})",
+        session.handle.as_str(),
+        session.did.as_str()
+    );
+    Ok(())
+}
+
+pub async fn logout(
+    persona: &Persona,
+    session_key: Option<&SecretKey>,
+) -> Result<(), Box<dyn Error>> {
+    let options = persona.session_options(session_key);
+    if api::logout(&persona.service, &persona.identifier, &options).await? {
+        println!("logged out {}", persona.identifier);
+    } else {
+        println!("no saved session for {}", persona.identifier);
+    }
+    Ok(())
+}
+
+pub async fn whoami(
+    persona: &Persona,
+    session_key: Option<&SecretKey>,
+) -> Result<(), Box<dyn Error>> {
+    let options = persona.session_options(session_key);
+    let Some(agent) = api::resume_agent(&persona.service, &persona.identifier, &options).await?
+    else {
+        return Err(format!(
+            "no usable saved session for {}, run `login` first",
+            persona.identifier
+        )
+        .into());
+    };
+    let session = agent.get_session().await.ok_or("session disappeared")?;
+    println!("did:                   {}", session.did.as_str());
+    println!("handle:                {}", session.handle.as_str());
+    println!("pds:                   {}", agent.get_endpoint().await);
+    println!(
+        "access token expires:  {}",
+        format_expiry(&session.access_jwt)
+    );
+    println!(
+        "refresh token expires: {}",
+        format_expiry(&session.refresh_jwt)
+    );
+    Ok(())
+}
+
+fn format_expiry(token: &str) -> String {
+    api::jwt_expiry(token)
+        .map(|t| t.to_rfc3339())
+        .unwrap_or_else(|| "unknown".to_string())
+}
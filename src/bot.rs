@@ -0,0 +1,505 @@
+use crate::api::{self, BskyClient};
+use crate::cache::{self, CachedDerailment, CachedVerdict, ThreadProgress, VerdictCache};
+use crate::config::Config;
+use crate::cost::{self, Budget, Price};
+use crate::crypto::SecretKey;
+use crate::data::{SideTracker, ThreadSummary, TimelineEntry};
+use crate::detector::{self, Derailment, Detector, Usage, Verdict};
+use crate::embedding;
+use crate::history::{History, HistoryEntry, Outcome};
+use crate::metrics::METRICS;
+use crate::persona::{self, Persona, Variant};
+use crate::post::{self, Post, PostLocator};
+use atrium_api::app::bsky::feed::post::RecordData;
+use atrium_api::com::atproto::repo::strong_ref;
+use atrium_api::types::string::Did;
+use chrono::Utc;
+use std::collections::{BTreeMap, VecDeque};
+use std::error::Error;
+use tracing::{debug, field, info, info_span, warn, Instrument, Span};
+
+/// A logged in persona, ready to check threads and reply to them.
+pub struct Bot {
+    pub persona: Persona,
+    pub agent: BskyClient,
+    pub did: Did,
+    variants: Vec<VariantDetector>,
+    parent_height: u16,
+    cache: Option<VerdictCache>,
+    history: Option<History>,
+    budget: Option<Budget>,
+}
+
+/// A variant of the experiment with its detector.
+struct VariantDetector {
+    variant: Variant,
+    prompt_id: String,
+    prompt_version: String,
+    detector: Box<dyn Detector>,
+    /// unknown for the models costing nothing
+    price: Option<Price>,
+}
+
+impl VariantDetector {
+    async fn new(
+        variant: Variant,
+        prices: &BTreeMap<String, Price>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let prompt = variant.load_prompt().await?;
+        let examples = variant.load_examples().await?;
+        // the examples shown to the model change the verdicts as much as the prompt does
+        let mut versioned = prompt.clone();
+        if !examples.is_empty() {
+            let shown = (variant.detector.few_shot.count, &examples);
+            versioned.push_str(&serde_json::to_string(&shown)?);
+        }
+        Ok(Self {
+            prompt_id: variant.prompt_id(),
+            prompt_version: cache::prompt_version(&versioned),
+            detector: detector::new_detector(&variant.detector, prompt, examples)?,
+            price: cost::price(prices, variant.detector.backend, &variant.detector.model),
+            variant,
+        })
+    }
+
+    /// What the tokens cost in USD, also counted in the metrics.
+    fn cost(&self, usage: Usage) -> f64 {
+        let cost = self.price.map_or(0.0, |price| price.cost(usage));
+        if cost > 0.0 {
+            METRICS
+                .llm_cost
+                .with_label_values(&[&self.variant.detector.model])
+                .inc_by(cost);
+        }
+        cost
+    }
+}
+
+impl Bot {
+    pub async fn new(
+        persona: Persona,
+        config: &Config,
+        session_key: Option<&SecretKey>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let agent = api::must_create_agent(
+            &persona.service,
+            &persona.identifier,
+            persona.password(),
+            &persona.session_options(session_key),
+        )
+        .await?;
+        let did = agent
+            .get_session()
+            .await
+            .ok_or("no session after logging in")?
+            .did
+            .clone();
+        let mut variants = Vec::new();
+        for variant in persona.variants() {
+            variants.push(VariantDetector::new(variant, &config.cost.prices).await?);
+        }
+        Ok(Self {
+            persona,
+            agent,
+            did,
+            variants,
+            parent_height: config.bluesky.parent_height,
+            cache: config.cache.enabled.then(|| config.cache.verdict_cache()),
+            history: config.history.enabled.then(|| config.history.history()),
+            budget: config.budget()?,
+        })
+    }
+
+    /// Which spending limit is reached, if any.
+    pub async fn exhausted(&self) -> Result<Option<String>, Box<dyn Error>> {
+        match self.budget {
+            Some(ref budget) => budget.exhausted().await,
+            None => Ok(None),
+        }
+    }
+
+    /// Fail if a spending limit is reached, before the detector is asked.
+    pub async fn ensure_budget(&self) -> Result<(), Box<dyn Error>> {
+        match self.exhausted().await? {
+            Some(reason) => Err(reason.into()),
+            None => Ok(()),
+        }
+    }
+
+    /// The variant assigned to the summon.
+    fn variant(&self, uri: &str) -> &VariantDetector {
+        let weights: Vec<u32> = self.variants.iter().map(|v| v.variant.weight).collect();
+        &self.variants[persona::assign_variant(&weights, uri)]
+    }
+
+    /// Check that the detectors of all variants are reachable.
+    pub async fn ping(&self) -> Result<(), Box<dyn Error>> {
+        for variant in &self.variants {
+            variant.detector.ping().await.map_err(|err| {
+                if self.variants.len() > 1 {
+                    format!("variant {}: {}", variant.variant.name, err).into()
+                } else {
+                    err
+                }
+            })?;
+        }
+        Ok(())
+    }
+
+    /// The span of a check, with the thread and the summoner to correlate its logs.
+    pub fn check_span(&self, thread: &str, summoner: Option<&Did>) -> Span {
+        let span = info_span!(
+            "check",
+            persona = %self.persona.name,
+            thread = %thread,
+            summoner = field::Empty,
+            variant = field::Empty,
+        );
+        if let Some(summoner) = summoner {
+            span.record("summoner", summoner.as_str());
+        }
+        span
+    }
+
+    /// Fetch the thread leading to the post, without the posts created after the summon.
+    pub(crate) async fn fetch(
+        &self,
+        uri: &str,
+    ) -> Result<(post::FlattenedThread, VecDeque<Post>), Box<dyn Error>> {
+        let res = api::get_post_thread(&self.agent, uri.to_string(), self.parent_height)
+            .instrument(info_span!("fetch"))
+            .await?;
+
+        let thread = post::FlattenedThread::from(&res);
+        let mut posts = VecDeque::from(&thread);
+        info!(posts = posts.len(), "fetched thread");
+        let summoned_at = thread.entrance.borrow().created_at;
+        if let Some(summoned_at) = summoned_at {
+            let dropped = post::drop_posts_after(&mut posts, summoned_at);
+            if dropped > 0 {
+                info!(dropped, "ignoring posts created after the summon");
+            }
+        }
+        Ok((thread, posts))
+    }
+
+    /// Find the sidetracker of the thread leading to the post.
+    pub async fn check(&self, uri: &str) -> Result<SideTracker, Box<dyn Error>> {
+        let (thread, posts) = self.fetch(uri).await?;
+        self.check_fetched(uri, &thread, &posts).await
+    }
+
+    /// Find the sidetracker of the fetched thread, failing when the detector does.
+    pub(crate) async fn check_fetched(
+        &self,
+        uri: &str,
+        thread: &post::FlattenedThread,
+        posts: &VecDeque<Post>,
+    ) -> Result<SideTracker, Box<dyn Error>> {
+        METRICS.checks.inc();
+        METRICS.thread_length.observe(posts.len() as f64);
+        let root = thread.root.borrow().uri.clone();
+        let variant = self.variant(uri);
+        Span::current().record("variant", variant.variant.name.as_str());
+        let Verdict {
+            derailments,
+            drift,
+            explanation,
+            rescuer,
+            ..
+        } = self.detect(variant, uri, &root, posts).await?;
+        info!(
+            sidetracker = derailments.first().map(|d| d.post.uri.as_str()),
+            derailments = derailments.len(),
+            "detected"
+        );
+        METRICS.verdict(!derailments.is_empty());
+        let result = SideTracker::from_derailments(
+            derailments,
+            thread.root.borrow().clone(),
+            thread.entrance.borrow().clone(),
+        )
+        .with_timeline(TimelineEntry::from_drift(posts, &drift))
+        .with_explanation(explanation)
+        .with_rescuer(rescuer);
+
+        debug!("side tracking result {:?}", result);
+        Ok(result)
+    }
+
+    /// Ask the detector, unless the same thread is checked recently. When the thread only grew
+    /// since a check finding nobody, just the new posts are checked. Detectors not scoring the
+    /// posts get the offline drift curve instead.
+    /// The verdict, or the failure, is recorded in the history together with the variant
+    /// producing it.
+    async fn detect(
+        &self,
+        variant: &VariantDetector,
+        uri: &str,
+        root: &str,
+        posts: &VecDeque<Post>,
+    ) -> Result<Verdict, Box<dyn Error>> {
+        let (verdict, cached) = match self.locate(variant, uri, root, posts).await {
+            Ok(located) => located,
+            Err(err) => {
+                let usage = Usage::of_failure(err.as_ref());
+                self.record(self.history_entry(variant, uri, Outcome::Failed, usage))
+                    .await;
+                return Err(err);
+            }
+        };
+        let mut verdict = if variant.variant.detector.spare_op {
+            verdict.sparing_op()
+        } else {
+            verdict
+        };
+        let outcome = if verdict.derailments.is_empty() {
+            Outcome::Clean
+        } else {
+            Outcome::Found
+        };
+        self.record(HistoryEntry {
+            derailments: verdict.derailments.len(),
+            cached,
+            ..self.history_entry(variant, uri, outcome, verdict.usage)
+        })
+        .await;
+        if verdict.drift.is_empty() {
+            verdict.drift = embedding::offline_drift(posts);
+        }
+        Ok(verdict)
+    }
+
+    fn history_entry(
+        &self,
+        variant: &VariantDetector,
+        uri: &str,
+        outcome: Outcome,
+        usage: Usage,
+    ) -> HistoryEntry {
+        HistoryEntry {
+            checked_at: Utc::now(),
+            persona: self.persona.name.clone(),
+            thread: uri.to_string(),
+            variant: variant.variant.name.clone(),
+            prompt_id: variant.prompt_id.clone(),
+            prompt_version: variant.prompt_version.clone(),
+            model: variant.detector.name(),
+            outcome,
+            derailments: 0,
+            cached: false,
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+            cost: variant.cost(usage),
+        }
+    }
+
+    async fn record(&self, entry: HistoryEntry) {
+        if let Some(ref history) = self.history {
+            if let Err(err) = history.append(&entry).await {
+                warn!("failed to record the verdict: {}", err);
+            }
+        }
+    }
+
+    /// The verdict of the variant, and whether it came from the cache.
+    async fn locate(
+        &self,
+        variant: &VariantDetector,
+        uri: &str,
+        root: &str,
+        posts: &VecDeque<Post>,
+    ) -> Result<(Verdict, bool), Box<dyn Error>> {
+        let name = variant.detector.name();
+        let sequence = cache::thread_sequence(posts);
+        let key = VerdictCache::key(&name, &variant.prompt_version, &sequence);
+        let mut checked = 0;
+        if let Some(ref cache) = self.cache {
+            if let Some(verdict) = cache.get(&key).await {
+                info!(key, "verdict from cache");
+                let verdict = Verdict {
+                    derailments: verdict
+                        .derail_points()
+                        .into_iter()
+                        .filter_map(|point| {
+                            let post = posts.iter().find(|p| p.idx == point.idx)?;
+                            Some(Derailment {
+                                post: post.clone(),
+                                topic: point.topic,
+                            })
+                        })
+                        .collect(),
+                    rescuer: verdict
+                        .rescuer
+                        .and_then(|idx| posts.iter().find(|p| p.idx == idx).cloned()),
+                    drift: verdict.drift,
+                    explanation: verdict.explanation,
+                    usage: Usage::default(),
+                };
+                return Ok((verdict, true));
+            }
+            if let Some(progress) = cache.get_progress(root).await {
+                checked = progress.checked_posts(&name, &variant.prompt_version, &sequence);
+            }
+        }
+        if checked > 0 {
+            info!(checked, "checking only the new posts");
+        }
+
+        let verdict = variant
+            .detector
+            .locate(posts, checked)
+            .instrument(info_span!("detect", detector = %name))
+            .await?;
+        if let Some(ref cache) = self.cache {
+            let cached = CachedVerdict {
+                model: name,
+                prompt_version: variant.prompt_version.clone(),
+                prompt_id: Some(variant.prompt_id.clone()),
+                variant: Some(variant.variant.name.clone()),
+                thread: uri.to_string(),
+                sidetracker: verdict.sidetracker().map(|p| p.idx),
+                checked_at: Utc::now(),
+                drift: verdict.drift.clone(),
+                explanation: verdict.explanation.clone(),
+                rescuer: verdict.rescuer.as_ref().map(|p| p.idx),
+                derailments: verdict
+                    .derailments
+                    .iter()
+                    .map(|d| CachedDerailment {
+                        idx: d.post.idx,
+                        topic: d.topic.clone(),
+                    })
+                    .collect(),
+            };
+            if let Err(err) = cache.put(&key, &cached).await {
+                warn!("failed to cache the verdict: {}", err);
+            }
+            let progress = ThreadProgress {
+                model: cached.model,
+                prompt_version: cached.prompt_version,
+                posts: sequence,
+                sidetracker: cached.sidetracker,
+                checked_at: cached.checked_at,
+            };
+            if let Err(err) = cache.put_progress(root, &progress).await {
+                warn!("failed to save the progress of the thread: {}", err);
+            }
+        }
+        Ok((verdict, false))
+    }
+
+    /// Summarize what the thread leading to the post was about and where it went.
+    pub async fn summarize(&self, uri: &str) -> Result<ThreadSummary, Box<dyn Error>> {
+        let (thread, posts) = self.fetch(uri).await?;
+        self.summarize_fetched(uri, &thread, &posts).await
+    }
+
+    /// Summarize the fetched thread, failing when the detector does.
+    pub(crate) async fn summarize_fetched(
+        &self,
+        uri: &str,
+        thread: &post::FlattenedThread,
+        posts: &VecDeque<Post>,
+    ) -> Result<ThreadSummary, Box<dyn Error>> {
+        let variant = self.variant(uri);
+        let detector = &variant.detector;
+        let summary = match detector
+            .summarize(posts)
+            .instrument(info_span!("summarize", detector = %detector.name()))
+            .await
+        {
+            Ok(summary) => summary,
+            Err(err) => {
+                // an answer that is no summary is paid for as well
+                let usage = Usage::of_failure(err.as_ref());
+                if usage != Usage::default() {
+                    self.record(self.history_entry(variant, uri, Outcome::Summarized, usage))
+                        .await;
+                }
+                return Err(err);
+            }
+        };
+        info!(topic = summary.topic, "summarized");
+        // summaries are paid for as well
+        self.record(self.history_entry(variant, uri, Outcome::Summarized, summary.usage))
+            .await;
+        let result = ThreadSummary::new(
+            summary,
+            thread.root.borrow().clone(),
+            thread.entrance.borrow().clone(),
+        );
+        Ok(result)
+    }
+
+    pub fn build_reply(&self, result: &SideTracker) -> RecordData {
+        result.build_reply(&self.persona.reply)
+    }
+
+    /// Post the reply, or print it out along with the verdict in dry run mode.
+    pub async fn publish(&self, result: &SideTracker, dry_run: bool) -> Result<(), Box<dyn Error>> {
+        let details = serde_json::json!({ "verdict": result.verdict_json() });
+        self.post_reply(self.build_reply(result), details, dry_run)
+            .await
+    }
+
+    /// Post the summary, or print it out in dry run mode.
+    pub async fn publish_summary(
+        &self,
+        summary: &ThreadSummary,
+        dry_run: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let details = serde_json::json!({ "summary": summary.summary_json() });
+        self.post_reply(summary.build_reply(&self.persona.reply), details, dry_run)
+            .await
+    }
+
+    /// Tell the summoner that a spending limit is reached, or print it out in dry run mode.
+    pub async fn publish_exhausted(
+        &self,
+        parent: strong_ref::Main,
+        root: strong_ref::Main,
+        reason: &str,
+        dry_run: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let template = &self.persona.reply;
+        if template.exhausted.is_empty() {
+            return Ok(());
+        }
+        let details = serde_json::json!({ "exhausted": reason });
+        self.post_reply(
+            template.notice(&template.exhausted, parent, root),
+            details,
+            dry_run,
+        )
+        .await
+    }
+
+    /// Post the reply. In dry run mode it is printed out instead, along with the details of how
+    /// it is made.
+    async fn post_reply(
+        &self,
+        reply: RecordData,
+        mut details: serde_json::Value,
+        dry_run: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        if dry_run {
+            debug!("dry run: not posting");
+            details["reply"] = serde_json::to_value(&reply)?;
+            println!("{}", serde_json::to_string_pretty(&details).unwrap());
+            return Ok(());
+        }
+        debug!("posting reply: {:?}", reply);
+        let result = api::create_record(&self.agent, reply)
+            .instrument(info_span!("post"))
+            .await?;
+        METRICS.replies.inc();
+        debug!("reply result: {:?}", result);
+        let locator = PostLocator::from_url(&result.uri);
+        debug!("reply result locator: {:?}", locator);
+        let app_uri = locator?.app_uri();
+        info!(reply = %app_uri, "reply published");
+        println!("reply published: {}", app_uri);
+        Ok(())
+    }
+}
@@ -0,0 +1,251 @@
+use crate::post::{self, Post};
+use std::collections::{HashSet, VecDeque};
+
+/// what an elision marker line costs in the prompt
+const MARKER_TOKENS: usize = 12;
+
+/// A line of the thread given to the detector.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Excerpt<'a> {
+    Post(&'a Post),
+    /// the posts from `first` to `last` (by `idx`) are left out
+    Elided {
+        first: u32,
+        last: u32,
+    },
+    /// the posts from `first` to `last` were judged on topic by an earlier check
+    Checked {
+        first: u32,
+        last: u32,
+    },
+}
+
+/// A rough token count: CJK and other non ASCII characters are about a token each, while English
+/// text is about four characters per token.
+pub fn estimate_tokens(text: &str) -> usize {
+    let (ascii, other) = text.chars().fold((0usize, 0usize), |(ascii, other), c| {
+        if c.is_ascii() {
+            (ascii + 1, other)
+        } else {
+            (ascii, other + 1)
+        }
+    });
+    ascii.div_ceil(4) + other
+}
+
+/// How many tokens the thread may take in a prompt for the model, unless configured.
+pub fn default_token_budget(model: &str) -> usize {
+    // the most specific prefix goes first
+    const BUDGETS: &[(&str, usize)] = &[
+        ("gpt-4o", 16_000),
+        ("gpt-4.1", 16_000),
+        ("gpt-4-turbo", 16_000),
+        ("gpt-4", 6_000),
+        ("gpt-3.5-turbo", 12_000),
+    ];
+    BUDGETS
+        .iter()
+        .find(|(prefix, _)| model.starts_with(prefix))
+        .map(|(_, budget)| *budget)
+        .unwrap_or(8_000)
+}
+
+/// The cost of a post as a line of the prompt.
+pub fn post_tokens(post: &Post) -> usize {
+    // the index, the author alias, the time since the previous post, the separator and the line
+    // break
+    estimate_tokens(&post.text) + 10
+}
+
+fn bigrams(text: &str) -> HashSet<(char, char)> {
+    let chars: Vec<char> = text.chars().filter(|c| !c.is_whitespace()).collect();
+    chars.windows(2).map(|w| (w[0], w[1])).collect()
+}
+
+/// How far each post moves away from the root and the post it replies to, from 0 to 1.
+fn drift_scores(thread: &VecDeque<Post>) -> Vec<f64> {
+    let root = thread.front().map(|p| bigrams(&p.text)).unwrap_or_default();
+    let mut scores = Vec::with_capacity(thread.len());
+    let mut previous = root.clone();
+    for post in thread {
+        let current = bigrams(&post.text);
+        let context: HashSet<_> = root.union(&previous).copied().collect();
+        let union = current.union(&context).count();
+        let score = if union == 0 {
+            0.0
+        } else {
+            1.0 - current.intersection(&context).count() as f64 / union as f64
+        };
+        scores.push(score);
+        previous = current;
+    }
+    scores
+}
+
+/// Fit the thread into the token budget. The head, i.e. the root and the post it quotes if any, and
+/// the last post are always kept, then the posts around the likely drift points, and the rest is
+/// elided with markers. The posts keep their `idx` so that the verdict maps back to them.
+///
+/// The first `checked` posts were judged on topic before, so only the head and the last of them
+/// are kept as the context of the new posts.
+pub fn fit_thread(thread: &VecDeque<Post>, checked: usize, budget: usize) -> Vec<Excerpt<'_>> {
+    let len = thread.len();
+    let head = post::topic_len(thread);
+    let skipped = if checked > head {
+        head..checked - 1
+    } else {
+        0..0
+    };
+    let candidates: Vec<bool> = (0..len).map(|i| !skipped.contains(&i)).collect();
+    let costs: Vec<usize> = thread.iter().map(post_tokens).collect();
+
+    let mut keep = candidates.clone();
+    let total: usize = (0..len).filter(|i| candidates[*i]).map(|i| costs[i]).sum();
+    if total + MARKER_TOKENS > budget {
+        keep = vec![false; len];
+        let mut used = 0;
+        // each kept post opens at most one gap
+        let mut take = |i: usize, keep: &mut Vec<bool>, force: bool| {
+            let cost = costs[i] + MARKER_TOKENS;
+            if candidates[i] && !keep[i] && (force || used + cost <= budget) {
+                keep[i] = true;
+                used += cost;
+            }
+        };
+        for i in (0..head).chain(len.checked_sub(1)) {
+            take(i, &mut keep, true);
+        }
+        let scores = drift_scores(thread);
+        let mut drifts: Vec<usize> = (1..len).filter(|i| candidates[*i]).collect();
+        drifts.sort_by(|a, b| scores[*b].total_cmp(&scores[*a]).then(a.cmp(b)));
+        for i in drifts {
+            // the drifting post, what it replied to and how the thread followed it
+            for j in [i, i - 1, i + 1] {
+                if j < len {
+                    take(j, &mut keep, false);
+                }
+            }
+        }
+    }
+
+    let mut excerpts = Vec::new();
+    // whether the posts of the gap were checked before, and the range of the gap
+    let mut gap: Option<(bool, u32, u32)> = None;
+    let flush = |gap: &mut Option<(bool, u32, u32)>, excerpts: &mut Vec<Excerpt>| match gap.take() {
+        Some((true, first, last)) => excerpts.push(Excerpt::Checked { first, last }),
+        Some((false, first, last)) => excerpts.push(Excerpt::Elided { first, last }),
+        None => {}
+    };
+    for (i, post) in thread.iter().enumerate() {
+        if keep[i] {
+            flush(&mut gap, &mut excerpts);
+            excerpts.push(Excerpt::Post(post));
+            continue;
+        }
+        let was_checked = skipped.contains(&i);
+        match gap {
+            Some((kind, first, _)) if kind == was_checked => {
+                gap = Some((kind, first, post.idx));
+            }
+            _ => {
+                flush(&mut gap, &mut excerpts);
+                gap = Some((was_checked, post.idx, post.idx));
+            }
+        }
+    }
+    flush(&mut gap, &mut excerpts);
+    excerpts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::post::test_thread;
+
+    fn kept_idx(excerpts: &[Excerpt]) -> Vec<u32> {
+        excerpts
+            .iter()
+            .filter_map(|e| match e {
+                Excerpt::Post(p) => Some(p.idx),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_estimate_tokens() {
+        assert_eq!(estimate_tokens(""), 0);
+        assert_eq!(estimate_tokens("abcd"), 1);
+        assert_eq!(estimate_tokens("hello world"), 3);
+        assert_eq!(estimate_tokens("猛吃！"), 3);
+        assert_eq!(estimate_tokens("ok 好"), 2);
+    }
+
+    #[test]
+    fn test_default_token_budget() {
+        assert_eq!(default_token_budget("gpt-4o-mini"), 16_000);
+        assert_eq!(default_token_budget("gpt-4-0613"), 6_000);
+        assert_eq!(default_token_budget("llama3"), 8_000);
+    }
+
+    #[test]
+    fn test_fit_short_thread() {
+        let thread = test_thread(&["a cat sat", "the cat sat on a mat", "a cat"]);
+        let excerpts = fit_thread(&thread, 0, 1000);
+        assert_eq!(kept_idx(&excerpts), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_fit_long_thread() {
+        let mut texts = vec!["what is the best way to cook rice"; 30];
+        texts[15] = "speaking of which, did anyone watch the football match yesterday";
+        texts[16] = "yes the football match was great";
+        let mut thread = test_thread(&texts);
+        thread[0].quoted = true;
+        let budget = 6 * (post_tokens(&thread[0]) + MARKER_TOKENS) + 10;
+        let excerpts = fit_thread(&thread, 0, budget);
+
+        let kept = kept_idx(&excerpts);
+        assert!(kept.starts_with(&[1, 2]));
+        assert_eq!(kept.last(), Some(&30));
+        // the drift point and its neighbours
+        assert!(kept.contains(&15) && kept.contains(&16) && kept.contains(&17));
+        assert!(kept.len() < 30);
+        assert!(excerpts.contains(&Excerpt::Elided { first: 3, last: 14 }));
+        assert_eq!(excerpts.first(), Some(&Excerpt::Post(&thread[0])));
+    }
+
+    #[test]
+    fn test_fit_new_posts() {
+        let texts: Vec<String> = (1..=10).map(|i| format!("post {}", i)).collect();
+        let texts: Vec<&str> = texts.iter().map(|t| t.as_str()).collect();
+        let mut thread = test_thread(&texts);
+        thread[0].quoted = true;
+
+        let excerpts = fit_thread(&thread, 7, 1000);
+        assert_eq!(kept_idx(&excerpts), vec![1, 2, 7, 8, 9, 10]);
+        assert_eq!(excerpts[2], Excerpt::Checked { first: 3, last: 6 });
+
+        // nothing to skip when only the head was checked
+        let excerpts = fit_thread(&thread, 2, 1000);
+        assert_eq!(kept_idx(&excerpts).len(), 10);
+
+        let budget = 5 * (post_tokens(&thread[0]) + MARKER_TOKENS);
+        let excerpts = fit_thread(&thread, 7, budget);
+        let kept = kept_idx(&excerpts);
+        assert!(kept.starts_with(&[1, 2]) && kept.ends_with(&[10]));
+        assert!(kept.iter().all(|idx| *idx <= 2 || *idx >= 7));
+        assert_eq!(excerpts[2], Excerpt::Checked { first: 3, last: 6 });
+    }
+
+    #[test]
+    fn test_fit_root_without_quote() {
+        let texts: Vec<String> = (1..=10).map(|i| format!("post {}", i)).collect();
+        let texts: Vec<&str> = texts.iter().map(|t| t.as_str()).collect();
+        let thread = test_thread(&texts);
+
+        let excerpts = fit_thread(&thread, 7, 1000);
+        assert_eq!(kept_idx(&excerpts), vec![1, 7, 8, 9, 10]);
+        assert_eq!(excerpts[1], Excerpt::Checked { first: 2, last: 6 });
+    }
+}
@@ -0,0 +1,478 @@
+use crate::detector::{DriftPoint, Explanation};
+use crate::post::Post;
+use crate::util;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// A detector verdict saved for re-checks of the same thread.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CachedVerdict {
+    pub model: String,
+    pub prompt_version: String,
+    /// the id of the prompt, none for the entries saved before prompts had ids
+    #[serde(default)]
+    pub prompt_id: Option<String>,
+    /// the experiment variant producing the verdict
+    #[serde(default)]
+    pub variant: Option<String>,
+    /// the post the check was summoned at
+    pub thread: String,
+    /// idx of the sidetracking post, none when nobody sidetracked
+    pub sidetracker: Option<u32>,
+    pub checked_at: DateTime<Utc>,
+    /// the drift curve measured by the detector
+    #[serde(default)]
+    pub drift: Vec<DriftPoint>,
+    /// the topic and the reason given in explain mode
+    #[serde(default)]
+    pub explanation: Option<Explanation>,
+    /// all the derail points in order, the first being the sidetracker
+    #[serde(default)]
+    pub derailments: Vec<CachedDerailment>,
+    /// idx of the post bringing the thread back on track
+    #[serde(default)]
+    pub rescuer: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CachedDerailment {
+    pub idx: u32,
+    pub topic: Option<String>,
+}
+
+impl CachedVerdict {
+    /// The derail points, or just the sidetracker for the entries saved before they were kept.
+    pub fn derail_points(&self) -> Vec<CachedDerailment> {
+        if !self.derailments.is_empty() {
+            return self.derailments.clone();
+        }
+        self.sidetracker
+            .map(|idx| CachedDerailment { idx, topic: None })
+            .into_iter()
+            .collect()
+    }
+
+    pub fn is_expired(&self, ttl: Duration, now: DateTime<Utc>) -> bool {
+        self.checked_at + ttl <= now
+    }
+}
+
+/// How far a thread is checked, so that only the new posts are checked when it grows.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ThreadProgress {
+    pub model: String,
+    pub prompt_version: String,
+    /// the `(idx, cid)` sequence of the checked posts
+    pub posts: Vec<(u32, String)>,
+    /// idx of the sidetracking post, none when nobody sidetracked
+    pub sidetracker: Option<u32>,
+    pub checked_at: DateTime<Utc>,
+}
+
+impl ThreadProgress {
+    /// How many leading posts of the thread are known to be on topic. It's 0, i.e. a full check,
+    /// unless nobody sidetracked in the same posts before with the same model and prompt.
+    pub fn checked_posts(
+        &self,
+        model: &str,
+        prompt_version: &str,
+        sequence: &[(u32, String)],
+    ) -> usize {
+        let grown = sequence.len() > self.posts.len() && sequence.starts_with(&self.posts);
+        if self.model == model
+            && self.prompt_version == prompt_version
+            && self.sidetracker.is_none()
+            && grown
+        {
+            self.posts.len()
+        } else {
+            0
+        }
+    }
+}
+
+/// What every entry has, to find the expired ones.
+#[derive(Deserialize)]
+struct Stamp {
+    checked_at: DateTime<Utc>,
+}
+
+/// The `(idx, cid)` sequence identifying the content of a thread. An edited post gets a new cid,
+/// and a deleted post shifts the idx of the later ones.
+pub fn thread_sequence<'a>(posts: impl IntoIterator<Item = &'a Post>) -> Vec<(u32, String)> {
+    posts
+        .into_iter()
+        .map(|p| (p.idx, p.cid.as_ref().to_string()))
+        .collect()
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// The default cache directory, `$XDG_CACHE_HOME/rust-sidetracker-bot/verdicts`.
+pub fn default_cache_dir() -> PathBuf {
+    util::xdg_app_dir("XDG_CACHE_HOME", ".cache").join("verdicts")
+}
+
+/// A short fingerprint of the prompt, so that editing the prompt invalidates the verdicts.
+pub fn prompt_version(prompt: &str) -> String {
+    sha256_hex(prompt.as_bytes())[..12].to_string()
+}
+
+/// Verdicts on disk, one file per key, and the progress of the threads in the `threads`
+/// subdirectory, one file per root.
+#[derive(Debug, Clone)]
+pub struct VerdictCache {
+    dir: PathBuf,
+    ttl: Duration,
+}
+
+impl VerdictCache {
+    pub fn new(dir: impl Into<PathBuf>, ttl: Duration) -> Self {
+        Self {
+            dir: dir.into(),
+            ttl,
+        }
+    }
+
+    /// The cache key of a check, a hash of the model, the prompt version and the `(idx, cid)`
+    /// sequence of the thread.
+    pub fn key(model: &str, prompt_version: &str, sequence: &[(u32, String)]) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(model.as_bytes());
+        hasher.update([0]);
+        hasher.update(prompt_version.as_bytes());
+        hasher.update([0]);
+        for (idx, cid) in sequence {
+            hasher.update(idx.to_be_bytes());
+            hasher.update(cid.as_bytes());
+            hasher.update([0]);
+        }
+        format!("{:x}", hasher.finalize())
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", key))
+    }
+
+    fn threads_dir(&self) -> PathBuf {
+        self.dir.join("threads")
+    }
+
+    fn progress_path(&self, root: &str) -> PathBuf {
+        self.threads_dir()
+            .join(format!("{}.json", sha256_hex(root.as_bytes())))
+    }
+
+    /// The unexpired verdict of the key. A broken entry is treated as a miss.
+    pub async fn get(&self, key: &str) -> Option<CachedVerdict> {
+        let path = self.path(key);
+        if !util::is_file_exists(&path).await {
+            return None;
+        }
+        match util::load_from_file::<CachedVerdict>(&path).await {
+            Ok(verdict) if !verdict.is_expired(self.ttl, Utc::now()) => Some(verdict),
+            Ok(_) => None,
+            Err(err) => {
+                warn!("ignoring broken cache entry {}: {}", path.display(), err);
+                None
+            }
+        }
+    }
+
+    pub async fn put(&self, key: &str, verdict: &CachedVerdict) -> Result<(), Box<dyn Error>> {
+        util::dump_to_private_file(self.path(key), verdict).await
+    }
+
+    /// The unexpired progress of the thread with the root.
+    pub async fn get_progress(&self, root: &str) -> Option<ThreadProgress> {
+        let path = self.progress_path(root);
+        if !util::is_file_exists(&path).await {
+            return None;
+        }
+        match util::load_from_file::<ThreadProgress>(&path).await {
+            Ok(progress) if progress.checked_at + self.ttl > Utc::now() => Some(progress),
+            Ok(_) => None,
+            Err(err) => {
+                warn!(
+                    "ignoring broken thread progress {}: {}",
+                    path.display(),
+                    err
+                );
+                None
+            }
+        }
+    }
+
+    pub async fn put_progress(
+        &self,
+        root: &str,
+        progress: &ThreadProgress,
+    ) -> Result<(), Box<dyn Error>> {
+        util::dump_to_private_file(self.progress_path(root), progress).await
+    }
+
+    /// All entries with their keys, the oldest first.
+    pub async fn list(&self) -> Result<Vec<(String, CachedVerdict)>, Box<dyn Error>> {
+        let mut entries = Vec::new();
+        for path in entry_paths(&self.dir).await? {
+            let Some(key) = path.file_stem().and_then(|s| s.to_str()) else {
+                continue;
+            };
+            match util::load_from_file::<CachedVerdict>(&path).await {
+                Ok(verdict) => entries.push((key.to_string(), verdict)),
+                Err(err) => warn!("ignoring broken cache entry {}: {}", path.display(), err),
+            }
+        }
+        entries.sort_by_key(|(_, verdict)| verdict.checked_at);
+        Ok(entries)
+    }
+
+    /// Remove the expired verdicts and thread progress, or all of them. Returns how many verdicts
+    /// are removed.
+    pub async fn clear(&self, expired_only: bool) -> Result<usize, Box<dyn Error>> {
+        let removed = self.clear_dir(&self.dir, expired_only).await?;
+        self.clear_dir(&self.threads_dir(), expired_only).await?;
+        Ok(removed)
+    }
+
+    async fn clear_dir(&self, dir: &Path, expired_only: bool) -> Result<usize, Box<dyn Error>> {
+        let now = Utc::now();
+        let mut removed = 0;
+        for path in entry_paths(dir).await? {
+            if expired_only {
+                // broken entries go away together with the expired ones
+                if let Ok(stamp) = util::load_from_file::<Stamp>(&path).await {
+                    if stamp.checked_at + self.ttl > now {
+                        continue;
+                    }
+                }
+            }
+            util::remove_file(&path).await?;
+            removed += 1;
+        }
+        Ok(removed)
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+}
+
+async fn entry_paths(dir: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let mut paths = Vec::new();
+    let mut entries = match tokio::fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(paths),
+        Err(err) => return Err(err.into()),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().is_some_and(|ext| ext == "json") {
+            paths.push(path);
+        }
+    }
+    Ok(paths)
+}
+
+/// Print the entries as a table, for the `cache list` command.
+pub async fn print_entries(cache: &VerdictCache) -> Result<(), Box<dyn Error>> {
+    let entries = cache.list().await?;
+    if entries.is_empty() {
+        println!("no cached verdict in {}", cache.dir().display());
+        return Ok(());
+    }
+    let now = Utc::now();
+    for (key, verdict) in entries {
+        println!(
+            "{}  {}  {} {}@{}  sidetracker: {}  {}{}",
+            &key[..12],
+            verdict.checked_at.to_rfc3339(),
+            verdict.model,
+            verdict.prompt_id.as_deref().unwrap_or("-"),
+            verdict.prompt_version,
+            verdict
+                .sidetracker
+                .map(|idx| idx.to_string())
+                .unwrap_or_else(|| "none".to_string()),
+            verdict.thread,
+            if verdict.is_expired(cache.ttl(), now) {
+                "  (expired)"
+            } else {
+                ""
+            }
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::post::test_post;
+    use atrium_api::types::string::Cid;
+    use std::str::FromStr;
+
+    fn post(idx: u32, cid: &str) -> Post {
+        Post {
+            cid: Cid::from_str(cid).unwrap(),
+            ..test_post(idx, "test.handle", "text")
+        }
+    }
+
+    const CID_A: &str = "bafyreihvgtbjqmyo2ocpfic3rgjtvepbopcfhsqwxynl2shc4cww3nnjly";
+    const CID_B: &str = "bafyreihvgtbjqmyo2ocpfic3rgjtvepbopaaaaawcccccsxxxxxw3nnjly";
+
+    fn verdict(checked_at: DateTime<Utc>) -> CachedVerdict {
+        CachedVerdict {
+            model: "gpt-4o-mini".to_string(),
+            prompt_version: prompt_version("prompt"),
+            prompt_id: Some("builtin".to_string()),
+            variant: None,
+            thread: "at://did:plc:test/app.bsky.feed.post/2".to_string(),
+            sidetracker: Some(2),
+            checked_at,
+            drift: Vec::new(),
+            explanation: None,
+            derailments: Vec::new(),
+            rescuer: None,
+        }
+    }
+
+    #[test]
+    fn test_key() {
+        let (a, b) = (post(1, CID_A), post(2, CID_B));
+        let sequence = thread_sequence([&a, &b]);
+        let key = VerdictCache::key("gpt-4o-mini", "v1", &sequence);
+        assert_eq!(key.len(), 64);
+        assert_eq!(key, VerdictCache::key("gpt-4o-mini", "v1", &sequence));
+        assert_ne!(key, VerdictCache::key("gpt-4o", "v1", &sequence));
+        assert_ne!(key, VerdictCache::key("gpt-4o-mini", "v2", &sequence));
+        assert_ne!(
+            key,
+            VerdictCache::key("gpt-4o-mini", "v1", &thread_sequence([&a]))
+        );
+        // an edited post gets a new cid
+        let edited = post(2, CID_A);
+        assert_ne!(
+            key,
+            VerdictCache::key("gpt-4o-mini", "v1", &thread_sequence([&a, &edited]))
+        );
+    }
+
+    #[test]
+    fn test_checked_posts() {
+        let posts = [post(1, CID_A), post(2, CID_B), post(3, CID_A)];
+        let progress = ThreadProgress {
+            model: "gpt-4o-mini".to_string(),
+            prompt_version: "v1".to_string(),
+            posts: thread_sequence(&posts[..2]),
+            sidetracker: None,
+            checked_at: Utc::now(),
+        };
+        let grown = thread_sequence(&posts);
+        assert_eq!(progress.checked_posts("gpt-4o-mini", "v1", &grown), 2);
+        assert_eq!(progress.checked_posts("gpt-4o", "v1", &grown), 0);
+        assert_eq!(progress.checked_posts("gpt-4o-mini", "v2", &grown), 0);
+        // nothing new
+        let same = thread_sequence(&posts[..2]);
+        assert_eq!(progress.checked_posts("gpt-4o-mini", "v1", &same), 0);
+        // the second post is edited
+        let edited = thread_sequence([&posts[0], &post(2, CID_A), &posts[2]]);
+        assert_eq!(progress.checked_posts("gpt-4o-mini", "v1", &edited), 0);
+
+        let found = ThreadProgress {
+            sidetracker: Some(2),
+            ..progress
+        };
+        assert_eq!(found.checked_posts("gpt-4o-mini", "v1", &grown), 0);
+    }
+
+    #[test]
+    fn test_prompt_version() {
+        assert_eq!(prompt_version("prompt").len(), 12);
+        assert_eq!(prompt_version("prompt"), prompt_version("prompt"));
+        assert_ne!(prompt_version("prompt"), prompt_version("prompt "));
+    }
+
+    #[test]
+    fn test_derail_points() {
+        let old = verdict(Utc::now());
+        assert_eq!(
+            old.derail_points(),
+            vec![CachedDerailment {
+                idx: 2,
+                topic: None
+            }]
+        );
+        let points = vec![
+            CachedDerailment {
+                idx: 2,
+                topic: Some("football".to_string()),
+            },
+            CachedDerailment {
+                idx: 5,
+                topic: Some("cats".to_string()),
+            },
+        ];
+        let verdict = CachedVerdict {
+            derailments: points.clone(),
+            ..old
+        };
+        assert_eq!(verdict.derail_points(), points);
+    }
+
+    #[tokio::test]
+    async fn test_get_put_clear() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = VerdictCache::new(dir.path().join("verdicts"), Duration::hours(1));
+        assert!(cache.list().await.unwrap().is_empty());
+        assert!(cache.get("fresh").await.is_none());
+
+        let fresh = verdict(Utc::now());
+        let stale = verdict(Utc::now() - Duration::hours(2));
+        cache.put("fresh", &fresh).await.unwrap();
+        cache.put("stale", &stale).await.unwrap();
+        assert_eq!(cache.get("fresh").await, Some(fresh.clone()));
+        assert!(cache.get("stale").await.is_none());
+
+        let keys: Vec<String> = cache
+            .list()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|e| e.0)
+            .collect();
+        assert_eq!(keys, vec!["stale", "fresh"]);
+
+        let progress = ThreadProgress {
+            model: "gpt-4o-mini".to_string(),
+            prompt_version: "v1".to_string(),
+            posts: vec![(1, CID_A.to_string())],
+            sidetracker: None,
+            checked_at: Utc::now(),
+        };
+        let root = "at://did:plc:test/app.bsky.feed.post/1";
+        cache.put_progress(root, &progress).await.unwrap();
+        assert_eq!(cache.get_progress(root).await, Some(progress));
+        assert!(cache.get_progress("at://other").await.is_none());
+        // the progress is not listed as a verdict
+        assert_eq!(cache.list().await.unwrap().len(), 2);
+
+        assert_eq!(cache.clear(true).await.unwrap(), 1);
+        assert!(cache.get_progress(root).await.is_some());
+        assert_eq!(cache.list().await.unwrap().len(), 1);
+        assert_eq!(cache.clear(false).await.unwrap(), 1);
+        assert!(cache.get("fresh").await.is_none());
+        assert!(cache.get_progress(root).await.is_none());
+    }
+}
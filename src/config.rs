@@ -0,0 +1,1175 @@
+use crate::budget;
+use crate::cache::{self, VerdictCache};
+use crate::cost::{Budget, Price};
+use crate::crypto::SecretKey;
+use crate::data::ReplyTemplate;
+use crate::history::{self, History};
+use crate::persona::{
+    self, DetectorSettings, Persona, Variant, DEFAULT_PASSWORD_ENV, DEFAULT_PERSONA,
+};
+use crate::util;
+use clap::builder::FalseyValueParser;
+use clap::Args;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+const REDACTED: &str = "<redacted>";
+
+/// Settings of the bot, layered as defaults < config file < env vars < command line flags.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// disable all post creation features
+    pub dry_run: bool,
+    pub bluesky: BlueskyConfig,
+    pub session: SessionConfig,
+    pub detector: DetectorConfig,
+    pub reply: ReplyTemplate,
+    pub daemon: DaemonConfig,
+    pub metrics: MetricsConfig,
+    pub cache: CacheConfig,
+    pub history: HistoryConfig,
+    pub cost: CostConfig,
+    pub experiment: ExperimentConfig,
+    /// bot accounts with their own personas. A single account is built from the `bluesky`
+    /// section when empty.
+    pub accounts: Vec<AccountConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BlueskyConfig {
+    /// the entryway to log in
+    pub service: String,
+    /// handle, DID or email of the bot account
+    pub identifier: Option<String>,
+    /// the env var holding the app password
+    pub password_env: String,
+    /// overrides the per-account session file in $XDG_STATE_HOME
+    pub session_file: Option<PathBuf>,
+    /// how many parent posts of the summoning post are checked
+    pub parent_height: u16,
+}
+
+impl Default for BlueskyConfig {
+    fn default() -> Self {
+        Self {
+            service: "https://bsky.social".to_string(),
+            identifier: None,
+            password_env: DEFAULT_PASSWORD_ENV.to_string(),
+            session_file: None,
+            parent_height: 200,
+        }
+    }
+}
+
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    /// encrypt the session files with a key derived from this passphrase
+    pub passphrase: Option<String>,
+    /// encrypt the session files with a key derived from the content of this file
+    pub key_file: Option<PathBuf>,
+}
+
+/// The secret as shown in logs.
+fn redact(secret: &Option<String>) -> Option<&str> {
+    secret.as_ref().map(|_| REDACTED)
+}
+
+// the passphrase unlocks the session files, so it never goes into the logs
+impl fmt::Debug for SessionConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionConfig")
+            .field("passphrase", &redact(&self.passphrase))
+            .field("key_file", &self.key_file)
+            .finish()
+    }
+}
+
+impl SessionConfig {
+    pub async fn secret_key(&self) -> Result<Option<SecretKey>, Box<dyn Error>> {
+        if let Some(ref passphrase) = self.passphrase {
+            return Ok(Some(SecretKey::from_passphrase(passphrase)));
+        }
+        if let Some(ref path) = self.key_file {
+            return Ok(Some(SecretKey::from_key_file(path).await?));
+        }
+        Ok(None)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DetectorBackend {
+    #[default]
+    OpenAi,
+    /// finds the drift from text embeddings, with no LLM
+    Embedding,
+    /// asks a model served by Ollama locally
+    Ollama,
+    /// asks a model served by the llama.cpp server locally, or any OpenAI compatible server
+    LlamaCpp,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DetectorConfig {
+    pub backend: DetectorBackend,
+    pub model: String,
+    /// the system prompt file, the built-in prompt is used if not set
+    pub prompt_file: Option<PathBuf>,
+    /// the id of the prompt recorded with every verdict, e.g. `en-3`. Defaults to the name of the
+    /// prompt file, or `builtin`.
+    pub prompt_id: Option<String>,
+    /// tokens a prompt may take by model, replacing the built-in budgets. Longer threads are
+    /// truncated to fit.
+    pub token_budgets: BTreeMap<String, usize>,
+    pub embedding: EmbeddingConfig,
+    pub local: LocalConfig,
+    pub openai: OpenAiConfig,
+    /// ask for the topic of the thread and why the sidetracker derails it, and include them in
+    /// the reply
+    pub explain: bool,
+    /// report up to this many posts derailing the thread, as threads may drift back and forth.
+    /// Only the first one is reported if 1.
+    pub max_derailments: usize,
+    /// also credit whoever brought the thread back to the original topic
+    pub find_rescuer: bool,
+    pub few_shot: FewShotConfig,
+    /// never blame the original poster: the OP changing the subject of their own thread is not a
+    /// sidetrack
+    pub spare_op: bool,
+}
+
+impl Default for DetectorConfig {
+    fn default() -> Self {
+        Self {
+            backend: DetectorBackend::default(),
+            model: "gpt-4o-mini".to_string(),
+            prompt_file: None,
+            prompt_id: None,
+            token_budgets: BTreeMap::new(),
+            embedding: EmbeddingConfig::default(),
+            local: LocalConfig::default(),
+            openai: OpenAiConfig::default(),
+            explain: false,
+            max_derailments: 1,
+            find_rescuer: false,
+            few_shot: FewShotConfig::default(),
+            spare_op: false,
+        }
+    }
+}
+
+/// Worked examples shown to the chat model before the thread.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FewShotConfig {
+    pub enabled: bool,
+    /// how many of the examples most relevant to the thread are shown
+    pub count: usize,
+    /// the example bank, a JSON array of `{"lang", "posts", "answer"}`. The built-in bank is used
+    /// if not set.
+    pub file: Option<PathBuf>,
+}
+
+impl Default for FewShotConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            count: 2,
+            file: None,
+        }
+    }
+}
+
+/// Settings of the embedding backend.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EmbeddingConfig {
+    /// an OpenAI compatible embeddings API, e.g. http://localhost:11434/v1. The built-in hashing
+    /// embedder is used if not set.
+    pub endpoint: Option<String>,
+    /// the embedding model of the endpoint
+    pub model: String,
+    /// how much the similarity of a sidetracking post drops from the posts before it
+    pub drop: f64,
+    /// the similarity to the topic, below which a post is off topic
+    pub low: f64,
+    /// how many posts, from the sidetracking one, stay off topic on average
+    pub window: usize,
+    /// seconds to wait for the endpoint
+    pub timeout: u64,
+}
+
+impl Default for EmbeddingConfig {
+    fn default() -> Self {
+        Self {
+            endpoint: None,
+            model: "nomic-embed-text".to_string(),
+            drop: 0.2,
+            low: 0.25,
+            window: 3,
+            timeout: 30,
+        }
+    }
+}
+
+/// Settings of the local chat backends, Ollama and the llama.cpp server.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LocalConfig {
+    /// the server, defaults to http://localhost:11434 for Ollama and http://localhost:8080 for
+    /// llama.cpp
+    pub url: Option<String>,
+    /// the sampling temperature, lower for steadier verdicts
+    pub temperature: f64,
+    /// seconds to wait for the answer, as a model on a CPU may be slow
+    pub timeout: u64,
+}
+
+impl Default for LocalConfig {
+    fn default() -> Self {
+        Self {
+            url: None,
+            temperature: 0.0,
+            timeout: 300,
+        }
+    }
+}
+
+/// The OpenAI compatible chat completions API of the openai backend.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OpenAiConfig {
+    /// the API, e.g. https://api.openai.com/v1 or that of another provider
+    pub base_url: String,
+    /// the env var holding the API key, no key is sent if empty
+    pub api_key_env: String,
+    /// sent as the `OpenAI-Organization` header if set
+    pub organization: Option<String>,
+    /// seconds to wait for the answer
+    pub timeout: u64,
+    /// seconds to wait for connecting to the API
+    pub connect_timeout: u64,
+    /// extra headers sent with every request, e.g. for a gateway in front of the API
+    pub headers: BTreeMap<String, String>,
+}
+
+impl Default for OpenAiConfig {
+    fn default() -> Self {
+        Self {
+            base_url: "https://api.openai.com/v1".to_string(),
+            api_key_env: "OPENAI_KEY".to_string(),
+            organization: None,
+            timeout: 60,
+            connect_timeout: 10,
+            headers: BTreeMap::new(),
+        }
+    }
+}
+
+impl DetectorConfig {
+    fn settings(&self, model: &str) -> DetectorSettings {
+        DetectorSettings {
+            backend: self.backend,
+            model: model.to_string(),
+            token_budget: self
+                .token_budgets
+                .get(model)
+                .copied()
+                .unwrap_or_else(|| budget::default_token_budget(model)),
+            embedding: self.embedding.clone(),
+            local: self.local.clone(),
+            openai: self.openai.clone(),
+            explain: self.explain,
+            max_derailments: self.max_derailments.max(1),
+            find_rescuer: self.find_rescuer,
+            few_shot: self.few_shot.clone(),
+            spare_op: self.spare_op,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DaemonConfig {
+    /// seconds between two polls of the notifications
+    pub poll_interval: u64,
+    /// how many notifications are fetched in a poll
+    pub notification_limit: u8,
+    /// a summon containing any of these words is answered with a summary of the thread instead
+    /// of the sidetracker, ignoring the case
+    pub summary_keywords: Vec<String>,
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        Self {
+            poll_interval: 30,
+            notification_limit: 50,
+            summary_keywords: vec![
+                "summarize".to_string(),
+                "summary".to_string(),
+                "总结".to_string(),
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// serve the Prometheus metrics and the health checks on this address in daemon mode,
+    /// disabled if not set
+    pub listen: Option<SocketAddr>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    /// reuse the verdict of a thread checked before with the same model and prompt, and check
+    /// only the new posts of a grown thread
+    pub enabled: bool,
+    /// seconds a verdict is reused
+    pub ttl: u64,
+    /// defaults to $XDG_CACHE_HOME/rust-sidetracker-bot/verdicts
+    pub dir: Option<PathBuf>,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            ttl: 24 * 60 * 60,
+            dir: None,
+        }
+    }
+}
+
+impl CacheConfig {
+    pub fn verdict_cache(&self) -> VerdictCache {
+        VerdictCache::new(
+            self.dir.clone().unwrap_or_else(cache::default_cache_dir),
+            chrono::Duration::seconds(self.ttl as i64),
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryConfig {
+    /// record every verdict with the prompt and the model producing it, for the stats
+    pub enabled: bool,
+    /// defaults to $XDG_STATE_HOME/rust-sidetracker-bot/history.jsonl
+    pub file: Option<PathBuf>,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            file: None,
+        }
+    }
+}
+
+/// What the chat models cost and how much may be spent on them.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CostConfig {
+    /// USD per million tokens by model, adding to and replacing the built-in prices of the
+    /// OpenAI models. Other models cost nothing.
+    pub prices: BTreeMap<String, Price>,
+    /// stop asking the models after spending this much in a day (UTC), all accounts together
+    pub daily_limit: Option<f64>,
+    /// stop asking the models after spending this much in a calendar month (UTC)
+    pub monthly_limit: Option<f64>,
+    /// what the daemon does once a limit is reached
+    pub when_exhausted: WhenExhausted,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WhenExhausted {
+    /// reply to the summons with the `exhausted` text of the persona
+    #[default]
+    Reply,
+    /// leave the summons unread until the budget renews
+    Pause,
+}
+
+impl HistoryConfig {
+    pub fn history(&self) -> History {
+        History::new(
+            self.file
+                .clone()
+                .unwrap_or_else(history::default_history_file),
+        )
+    }
+}
+
+/// Splits the summons between prompt or model variants, to compare their verdicts in the stats.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExperimentConfig {
+    /// no experiment runs when empty
+    pub variants: Vec<VariantConfig>,
+}
+
+/// A variant of an experiment. Settings not given here are inherited from the persona.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VariantConfig {
+    pub name: String,
+    /// the share of the summons given to this variant, relative to the other variants
+    pub weight: u32,
+    pub prompt_file: Option<PathBuf>,
+    pub prompt_id: Option<String>,
+    pub model: Option<String>,
+}
+
+/// A bot account. Settings not given here are inherited from the global sections.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccountConfig {
+    /// the name to select the persona with
+    pub name: String,
+    /// handle, DID or email of the account
+    pub identifier: String,
+    pub service: Option<String>,
+    pub password_env: Option<String>,
+    pub session_file: Option<PathBuf>,
+    pub prompt_file: Option<PathBuf>,
+    pub prompt_id: Option<String>,
+    pub model: Option<String>,
+    /// replaces the global reply template
+    pub reply: Option<ReplyTemplate>,
+    /// handles or DIDs allowed to summon the bot in daemon mode, everyone is allowed when empty
+    #[serde(default)]
+    pub allowlist: Vec<String>,
+    /// laid over the global detector section, so that only the settings differing from it are
+    /// given, e.g. `[accounts.detector.openai]` with only the `base_url`
+    pub detector: Option<toml::Table>,
+}
+
+/// Settings from env vars and command line flags, which take priority over the config file.
+#[derive(Args, Default, Clone)]
+pub struct Overrides {
+    // switches take an optional value, e.g. `--explain=false`, to turn off what the file turns on
+    #[arg(
+        short = 'n',
+        long,
+        global = true,
+        env = "DRY_RUN",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        value_parser = FalseyValueParser::new()
+    )]
+    /// disable all post creation features.
+    pub dry_run: Option<bool>,
+
+    #[arg(long, global = true, env = "BLUESKY_SERVICE")]
+    /// the Bluesky entryway to log in.
+    pub service: Option<String>,
+
+    #[arg(long, global = true, env = "BLUESKY_IDENTIFIER")]
+    /// handle, DID or email of the bot account, used when no account is configured.
+    pub identifier: Option<String>,
+
+    #[arg(long, global = true, env = "SESSION_FILE")]
+    /// save the session into this file instead of the per-account file in $XDG_STATE_HOME.
+    /// Used when no account is configured.
+    pub session_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        global = true,
+        env = "SESSION_PASSPHRASE",
+        hide_env_values = true
+    )]
+    /// encrypt the saved session with a key derived from this passphrase.
+    pub session_passphrase: Option<String>,
+
+    #[arg(
+        long,
+        global = true,
+        env = "SESSION_KEY_FILE",
+        conflicts_with = "session_passphrase"
+    )]
+    /// encrypt the saved session with a key derived from the content of this file.
+    pub session_key_file: Option<PathBuf>,
+
+    #[arg(long, global = true, env = "OPENAI_MODEL")]
+    /// the LLM model, unless set by the account.
+    pub model: Option<String>,
+
+    #[arg(long, global = true, env = "OPENAI_BASE_URL")]
+    /// the OpenAI compatible API of the openai backend.
+    pub openai_base_url: Option<String>,
+
+    #[arg(long, global = true, env = "PROMPT_FILE")]
+    /// the system prompt file, unless set by the account.
+    pub prompt_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        global = true,
+        env = "EXPLAIN",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        value_parser = FalseyValueParser::new()
+    )]
+    /// explain in the reply what the thread was about and why the sidetracker derails it.
+    pub explain: Option<bool>,
+
+    #[arg(long, global = true, env = "MAX_DERAILMENTS")]
+    /// name up to this many posts derailing the thread instead of only the first one.
+    pub max_derailments: Option<usize>,
+
+    #[arg(
+        long,
+        global = true,
+        env = "FIND_RESCUER",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        value_parser = FalseyValueParser::new()
+    )]
+    /// also name whoever brought the thread back to the original topic.
+    pub find_rescuer: Option<bool>,
+
+    #[arg(
+        long,
+        global = true,
+        env = "SPARE_OP",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        value_parser = FalseyValueParser::new()
+    )]
+    /// never blame the original poster for changing the subject of their own thread.
+    pub spare_op: Option<bool>,
+
+    #[arg(
+        long,
+        global = true,
+        env = "NO_FEW_SHOT",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        value_parser = FalseyValueParser::new()
+    )]
+    /// ask the detector without showing it worked examples first.
+    pub no_few_shot: Option<bool>,
+
+    #[arg(long, global = true, env = "POLL_INTERVAL")]
+    /// seconds between two polls of the notifications in daemon mode.
+    pub poll_interval: Option<u64>,
+
+    #[arg(
+        long,
+        global = true,
+        env = "NO_CACHE",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        value_parser = FalseyValueParser::new()
+    )]
+    /// ask the detector even if the thread is checked recently.
+    pub no_cache: Option<bool>,
+
+    #[arg(long, global = true, env = "METRICS_LISTEN")]
+    /// serve /metrics, /healthz and /readyz on this address in daemon mode, e.g. 127.0.0.1:9464.
+    pub metrics_listen: Option<SocketAddr>,
+}
+
+impl fmt::Debug for Overrides {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Overrides")
+            .field("dry_run", &self.dry_run)
+            .field("service", &self.service)
+            .field("identifier", &self.identifier)
+            .field("session_file", &self.session_file)
+            .field("session_passphrase", &redact(&self.session_passphrase))
+            .field("session_key_file", &self.session_key_file)
+            .field("model", &self.model)
+            .field("openai_base_url", &self.openai_base_url)
+            .field("prompt_file", &self.prompt_file)
+            .field("explain", &self.explain)
+            .field("max_derailments", &self.max_derailments)
+            .field("find_rescuer", &self.find_rescuer)
+            .field("spare_op", &self.spare_op)
+            .field("no_few_shot", &self.no_few_shot)
+            .field("poll_interval", &self.poll_interval)
+            .field("no_cache", &self.no_cache)
+            .field("metrics_listen", &self.metrics_listen)
+            .finish()
+    }
+}
+
+/// The default config file, `$XDG_CONFIG_HOME/rust-sidetracker-bot/config.toml`.
+pub fn default_config_file() -> PathBuf {
+    util::xdg_app_dir("XDG_CONFIG_HOME", ".config").join("config.toml")
+}
+
+impl Config {
+    /// Load the config file. A missing default config file means all defaults, while a missing
+    /// file given explicitly is an error.
+    pub async fn load(path: Option<&Path>) -> Result<Self, Box<dyn Error>> {
+        let (path, required) = match path {
+            Some(path) => (path.to_owned(), true),
+            None => (default_config_file(), false),
+        };
+        if !util::is_file_exists(&path).await {
+            if required {
+                return Err(format!("config file {} not found", path.display()).into());
+            }
+            return Ok(Self::default());
+        }
+        let content = tokio::fs::read_to_string(&path).await?;
+        Self::parse(&content)
+            .map_err(|err| format!("invalid config file {}: {}", path.display(), err).into())
+    }
+
+    pub fn parse(content: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(content)
+    }
+
+    pub fn apply(&mut self, overrides: &Overrides) {
+        if let Some(dry_run) = overrides.dry_run {
+            self.dry_run = dry_run;
+        }
+        if let Some(ref service) = overrides.service {
+            self.bluesky.service = service.clone();
+        }
+        if overrides.identifier.is_some() {
+            self.bluesky.identifier = overrides.identifier.clone();
+        }
+        if overrides.session_file.is_some() {
+            self.bluesky.session_file = overrides.session_file.clone();
+        }
+        // the passphrase and the key file are exclusive
+        if overrides.session_passphrase.is_some() {
+            self.session.passphrase = overrides.session_passphrase.clone();
+            self.session.key_file = None;
+        }
+        if overrides.session_key_file.is_some() {
+            self.session.key_file = overrides.session_key_file.clone();
+            self.session.passphrase = None;
+        }
+        if let Some(ref model) = overrides.model {
+            self.detector.model = model.clone();
+        }
+        if let Some(ref base_url) = overrides.openai_base_url {
+            self.detector.openai.base_url = base_url.clone();
+        }
+        if overrides.prompt_file.is_some() {
+            self.detector.prompt_file = overrides.prompt_file.clone();
+        }
+        if let Some(explain) = overrides.explain {
+            self.detector.explain = explain;
+        }
+        if let Some(find_rescuer) = overrides.find_rescuer {
+            self.detector.find_rescuer = find_rescuer;
+        }
+        if let Some(spare_op) = overrides.spare_op {
+            self.detector.spare_op = spare_op;
+        }
+        if let Some(no_few_shot) = overrides.no_few_shot {
+            self.detector.few_shot.enabled = !no_few_shot;
+        }
+        if let Some(max_derailments) = overrides.max_derailments {
+            self.detector.max_derailments = max_derailments;
+        }
+        if let Some(poll_interval) = overrides.poll_interval {
+            self.daemon.poll_interval = poll_interval;
+        }
+        if let Some(no_cache) = overrides.no_cache {
+            self.cache.enabled = !no_cache;
+        }
+        if overrides.metrics_listen.is_some() {
+            self.metrics.listen = overrides.metrics_listen;
+        }
+    }
+
+    /// A copy safe to be printed out.
+    pub fn redacted(&self) -> Self {
+        let mut config = self.clone();
+        if config.session.passphrase.is_some() {
+            config.session.passphrase = Some(REDACTED.to_string());
+        }
+        // the headers may carry credentials of a gateway
+        for value in config.detector.openai.headers.values_mut() {
+            *value = REDACTED.to_string();
+        }
+        config
+    }
+
+    /// The spending limits, if any, which are checked against the costs recorded in the history.
+    pub fn budget(&self) -> Result<Option<Budget>, Box<dyn Error>> {
+        let (daily_limit, monthly_limit) = (self.cost.daily_limit, self.cost.monthly_limit);
+        if daily_limit.is_none() && monthly_limit.is_none() {
+            return Ok(None);
+        }
+        if !self.history.enabled {
+            return Err("the spending limits need the history, enable it in [history]".into());
+        }
+        Ok(Some(Budget::new(
+            self.history.history(),
+            daily_limit,
+            monthly_limit,
+        )))
+    }
+
+    /// The personas of the configured accounts.
+    pub fn personas(&self) -> Result<Vec<Persona>, Box<dyn Error>> {
+        let detector = self.detector.settings(&self.detector.model);
+        if self.accounts.is_empty() {
+            let identifier = self.bluesky.identifier.as_deref().ok_or(
+                "the bot account is required, set BLUESKY_IDENTIFIER, --identifier or accounts in the config file",
+            )?;
+            return Ok(vec![Persona {
+                service: self.bluesky.service.clone(),
+                password_env: self.bluesky.password_env.clone(),
+                session_file: self.bluesky.session_file.clone(),
+                prompt_file: self.detector.prompt_file.clone(),
+                prompt_id: self.detector.prompt_id.clone(),
+                reply: self.reply.clone(),
+                variants: self.variants(
+                    &self.detector,
+                    &self.detector.prompt_file,
+                    &self.detector.prompt_id,
+                    &self.detector.model,
+                ),
+                detector,
+                ..Persona::new(DEFAULT_PERSONA, identifier)
+            }]);
+        }
+        let personas = self
+            .accounts
+            .iter()
+            .map(|account| {
+                let detector = self.account_detector(account)?;
+                // the global prompt id names the global prompt file only
+                let (prompt_file, prompt_id) = match account.prompt_file {
+                    Some(ref file) => (Some(file.clone()), account.prompt_id.clone()),
+                    None => (
+                        detector.prompt_file.clone(),
+                        account
+                            .prompt_id
+                            .clone()
+                            .or_else(|| detector.prompt_id.clone()),
+                    ),
+                };
+                let model = account.model.as_deref().unwrap_or(&detector.model);
+                Ok(Persona {
+                    name: account.name.clone(),
+                    identifier: account.identifier.clone(),
+                    service: account
+                        .service
+                        .clone()
+                        .unwrap_or_else(|| self.bluesky.service.clone()),
+                    password_env: account
+                        .password_env
+                        .clone()
+                        .unwrap_or_else(|| self.bluesky.password_env.clone()),
+                    session_file: account.session_file.clone(),
+                    reply: account.reply.clone().unwrap_or_else(|| self.reply.clone()),
+                    allowlist: account.allowlist.clone(),
+                    detector: detector.settings(model),
+                    variants: self.variants(&detector, &prompt_file, &prompt_id, model),
+                    prompt_file,
+                    prompt_id,
+                })
+            })
+            .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+        persona::validate_personas(&personas)?;
+        Ok(personas)
+    }
+
+    /// The detector config of the account, its `[accounts.detector]` table laid over the global
+    /// one.
+    fn account_detector(&self, account: &AccountConfig) -> Result<DetectorConfig, Box<dyn Error>> {
+        let Some(ref overlay) = account.detector else {
+            return Ok(self.detector.clone());
+        };
+        let mut detector = toml::Table::try_from(&self.detector)?;
+        merge_table(&mut detector, overlay);
+        detector.try_into().map_err(|err| {
+            format!(
+                "invalid detector settings of account {}: {}",
+                account.name, err
+            )
+            .into()
+        })
+    }
+
+    /// The variants of the experiment for a persona with the prompt and the model.
+    fn variants(
+        &self,
+        detector: &DetectorConfig,
+        prompt_file: &Option<PathBuf>,
+        prompt_id: &Option<String>,
+        model: &str,
+    ) -> Vec<Variant> {
+        self.experiment
+            .variants
+            .iter()
+            .map(|variant| Variant {
+                name: variant.name.clone(),
+                weight: variant.weight,
+                prompt_file: variant.prompt_file.clone().or_else(|| prompt_file.clone()),
+                prompt_id: match variant.prompt_file {
+                    Some(_) => variant.prompt_id.clone(),
+                    None => variant.prompt_id.clone().or_else(|| prompt_id.clone()),
+                },
+                detector: detector.settings(variant.model.as_deref().unwrap_or(model)),
+            })
+            .collect()
+    }
+}
+
+/// Lay the overlay over the base table, merging the tables nested in both.
+fn merge_table(base: &mut toml::Table, overlay: &toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(overlay)) => {
+                merge_table(base, overlay)
+            }
+            _ => {
+                base.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_CONFIG: &str = r#"
+        [bluesky]
+        parent_height = 50
+
+        [detector]
+        model = "gpt-4o"
+
+        [detector.token_budgets]
+        "gpt-4o-mini" = 4000
+
+        [detector.openai.headers]
+        "X-Gateway-Key" = "gateway-secret"
+
+        [session]
+        passphrase = "secret"
+
+        [daemon]
+        poll_interval = 10
+
+        [metrics]
+        listen = "127.0.0.1:9464"
+
+        [[accounts]]
+        name = "zh"
+        identifier = "zh.bot.handle"
+
+        [[accounts]]
+        name = "en"
+        identifier = "en.bot.handle"
+        password_env = "EN_BOT_PASSWORD"
+        prompt_file = "data/prompt_en.txt"
+        model = "gpt-4o-mini"
+        allowlist = ["friend.handle"]
+
+        [accounts.reply]
+        culprit = "Most likely sidetracker: "
+        evidence = "Evidence: "
+        not_found = "Great, nobody sidetracked"
+        langs = ["en-US"]
+    "#;
+
+    #[test]
+    fn test_defaults() {
+        let config = Config::parse("").unwrap();
+        assert_eq!(config, Config::default());
+        assert_eq!(config.bluesky.service, "https://bsky.social");
+        assert_eq!(config.bluesky.parent_height, 200);
+        assert_eq!(config.detector.model, "gpt-4o-mini");
+        assert!(config.personas().is_err());
+    }
+
+    #[test]
+    fn test_unknown_field() {
+        assert!(Config::parse("[bluesky]\nhandle = \"test\"").is_err());
+    }
+
+    #[test]
+    fn test_file_layer() {
+        let config = Config::parse(TEST_CONFIG).unwrap();
+        assert_eq!(config.bluesky.parent_height, 50);
+        assert_eq!(config.daemon.poll_interval, 10);
+        assert_eq!(config.daemon.notification_limit, 50);
+        assert_eq!(
+            config.metrics.listen,
+            Some("127.0.0.1:9464".parse().unwrap())
+        );
+
+        let personas = config.personas().unwrap();
+        assert_eq!(personas.len(), 2);
+        assert_eq!(personas[0].name, "zh");
+        assert_eq!(personas[0].detector.model, "gpt-4o");
+        assert_eq!(personas[0].detector.token_budget, 16_000);
+        assert_eq!(personas[0].password_env, "BLUESKY_PASSWORD");
+        assert_eq!(personas[0].reply, ReplyTemplate::default());
+        assert_eq!(personas[1].detector.model, "gpt-4o-mini");
+        assert_eq!(personas[1].detector.token_budget, 4000);
+        assert_eq!(personas[1].password_env, "EN_BOT_PASSWORD");
+        assert_eq!(personas[1].reply.langs, vec!["en-US"]);
+        assert_eq!(personas[1].allowlist, vec!["friend.handle"]);
+    }
+
+    #[test]
+    fn test_override_layer() {
+        let mut config = Config::parse(TEST_CONFIG).unwrap();
+        config.apply(&Overrides {
+            dry_run: Some(true),
+            model: Some("o1".to_string()),
+            openai_base_url: Some("http://localhost:4000/v1".to_string()),
+            session_key_file: Some(PathBuf::from("/tmp/key")),
+            poll_interval: Some(5),
+            explain: Some(true),
+            max_derailments: Some(3),
+            spare_op: Some(true),
+            ..Default::default()
+        });
+        assert!(config.dry_run);
+        assert!(config.detector.explain);
+        assert_eq!(config.detector.model, "o1");
+        assert_eq!(config.detector.openai.base_url, "http://localhost:4000/v1");
+        assert_eq!(config.session.passphrase, None);
+        assert_eq!(config.daemon.poll_interval, 5);
+
+        let personas = config.personas().unwrap();
+        // settings of accounts are more specific than the global overrides
+        assert_eq!(personas[0].detector.model, "o1");
+        assert_eq!(personas[1].detector.model, "gpt-4o-mini");
+        assert!(personas[1].detector.explain);
+        assert_eq!(personas[1].detector.max_derailments, 3);
+        assert!(personas[1].detector.spare_op);
+        assert_eq!(personas[1].detector.openai.timeout, 60);
+    }
+
+    #[test]
+    fn test_override_switches_off() {
+        #[derive(clap::Parser)]
+        struct Cli {
+            #[command(flatten)]
+            overrides: Overrides,
+        }
+        let parse = |args: &[&str]| {
+            <Cli as clap::Parser>::try_parse_from([&["test"], args].concat())
+                .unwrap()
+                .overrides
+        };
+
+        let mut config = Config::parse(
+            r#"
+            dry_run = true
+
+            [detector]
+            explain = true
+
+            [cache]
+            enabled = false
+            "#,
+        )
+        .unwrap();
+        config.apply(&parse(&[]));
+        assert!(config.dry_run);
+        assert!(config.detector.explain);
+        assert!(!config.cache.enabled);
+
+        let overrides = parse(&[
+            "--dry-run=false",
+            "--explain=no",
+            "--no-cache=0",
+            "--spare-op",
+        ]);
+        assert_eq!(overrides.find_rescuer, None);
+        config.apply(&overrides);
+        assert!(!config.dry_run);
+        assert!(!config.detector.explain);
+        assert!(config.cache.enabled);
+        assert!(config.detector.spare_op);
+    }
+
+    #[test]
+    fn test_experiment() {
+        let config = Config::parse(
+            r#"
+            [detector]
+            prompt_id = "zh-2"
+
+            [[experiment.variants]]
+            name = "control"
+            weight = 3
+
+            [[experiment.variants]]
+            name = "english"
+            weight = 1
+            prompt_file = "data/prompt_en.txt"
+            model = "gpt-4o"
+
+            [[accounts]]
+            name = "zh"
+            identifier = "zh.bot.handle"
+
+            [[accounts]]
+            name = "en"
+            identifier = "en.bot.handle"
+            prompt_file = "data/prompt_en.txt"
+            "#,
+        )
+        .unwrap();
+        let personas = config.personas().unwrap();
+        let variants = personas[0].variants();
+        assert_eq!(variants.len(), 2);
+        assert_eq!(variants[0].weight, 3);
+        assert_eq!(variants[0].prompt_id(), "zh-2");
+        assert_eq!(variants[0].detector.model, "gpt-4o-mini");
+        assert_eq!(variants[1].prompt_id(), "prompt_en");
+        assert_eq!(variants[1].detector.model, "gpt-4o");
+        assert_eq!(variants[1].detector.token_budget, 16_000);
+
+        // the account's own prompt file is not named after the global prompt id
+        let variants = personas[1].variants();
+        assert_eq!(variants[0].prompt_id(), "prompt_en");
+        assert_eq!(variants[0].prompt_file, personas[1].prompt_file);
+
+        let mut config = config;
+        config.experiment.variants[1].name = "control".to_string();
+        assert!(config.personas().is_err());
+    }
+
+    #[test]
+    fn test_account_detector() {
+        let mut config = Config::parse(
+            r#"
+            [detector]
+            explain = true
+
+            [detector.openai]
+            base_url = "http://localhost:4000/v1"
+
+            [[accounts]]
+            name = "zh"
+            identifier = "zh.bot.handle"
+
+            [[accounts]]
+            name = "en"
+            identifier = "en.bot.handle"
+
+            [accounts.detector]
+            backend = "embedding"
+            model = "gpt-4o"
+            max_derailments = 2
+
+            [accounts.detector.openai]
+            timeout = 30
+            "#,
+        )
+        .unwrap();
+        let personas = config.personas().unwrap();
+        let (zh, en) = (&personas[0].detector, &personas[1].detector);
+        assert_eq!(zh.backend, DetectorBackend::OpenAi);
+        assert_eq!(zh.model, "gpt-4o-mini");
+        assert_eq!(zh.max_derailments, 1);
+        assert_eq!(zh.openai.timeout, 60);
+        assert_eq!(en.backend, DetectorBackend::Embedding);
+        assert_eq!(en.model, "gpt-4o");
+        assert_eq!(en.token_budget, 16_000);
+        assert_eq!(en.max_derailments, 2);
+        assert_eq!(en.openai.timeout, 30);
+        // the rest is inherited from the global section
+        assert!(en.explain);
+        assert_eq!(en.openai.base_url, "http://localhost:4000/v1");
+        assert_eq!(personas[1].variants()[0].detector.backend, en.backend);
+
+        config.accounts[1]
+            .detector
+            .as_mut()
+            .unwrap()
+            .insert("typo".to_string(), toml::Value::Boolean(true));
+        assert!(config.personas().is_err());
+    }
+
+    #[test]
+    fn test_single_account_from_overrides() {
+        let mut config = Config::default();
+        config.apply(&Overrides {
+            identifier: Some("bot.handle".to_string()),
+            session_file: Some(PathBuf::from("/tmp/session.json")),
+            ..Default::default()
+        });
+        let personas = config.personas().unwrap();
+        assert_eq!(personas.len(), 1);
+        assert_eq!(personas[0].name, DEFAULT_PERSONA);
+        assert_eq!(personas[0].identifier, "bot.handle");
+        assert_eq!(
+            personas[0].session_file,
+            Some(PathBuf::from("/tmp/session.json"))
+        );
+    }
+
+    #[test]
+    fn test_cost() {
+        assert!(Config::default().budget().unwrap().is_none());
+        let mut config = Config::parse(
+            r#"
+            [cost]
+            daily_limit = 1.5
+            when_exhausted = "pause"
+
+            [cost.prices."qwen2.5:7b"]
+            prompt = 0.02
+            completion = 0.04
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.cost.when_exhausted, WhenExhausted::Pause);
+        assert_eq!(config.cost.prices["qwen2.5:7b"].completion, 0.04);
+        assert!(config.budget().unwrap().is_some());
+        config.history.enabled = false;
+        assert!(config.budget().is_err());
+        assert!(Config::parse("[cost.prices.o1]\nprompt = 15.0").is_err());
+    }
+
+    #[test]
+    fn test_redacted() {
+        let config = Config::parse(TEST_CONFIG).unwrap();
+        let shown = toml::to_string_pretty(&config.redacted()).unwrap();
+        assert!(!shown.contains("secret"));
+        assert!(shown.contains(REDACTED));
+        assert!(Config::parse(&shown).is_ok());
+
+        let logged = format!("{:?}", config.session);
+        assert!(!logged.contains("secret") && logged.contains(REDACTED));
+        let overrides = Overrides {
+            session_passphrase: Some("hunter2".to_string()),
+            model: Some("o1".to_string()),
+            ..Default::default()
+        };
+        let logged = format!("{:?}", overrides);
+        assert!(!logged.contains("hunter2"));
+        assert!(logged.contains(REDACTED) && logged.contains("o1"));
+        assert!(!format!("{:?}", Overrides::default()).contains(REDACTED));
+    }
+}
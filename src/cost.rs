@@ -0,0 +1,194 @@
+use crate::config::DetectorBackend;
+use crate::detector::Usage;
+use crate::history::{History, HistoryEntry};
+use chrono::{DateTime, Datelike, NaiveTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+
+/// What a model costs, in USD per million tokens.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Price {
+    pub prompt: f64,
+    pub completion: f64,
+}
+
+impl Price {
+    pub fn cost(&self, usage: Usage) -> f64 {
+        (usage.prompt_tokens as f64 * self.prompt
+            + usage.completion_tokens as f64 * self.completion)
+            / 1_000_000.0
+    }
+}
+
+/// The price of the model, as configured or else as listed by OpenAI for the openai backend.
+/// Models of unknown price, e.g. those served locally, cost nothing.
+pub fn price(
+    prices: &BTreeMap<String, Price>,
+    backend: DetectorBackend,
+    model: &str,
+) -> Option<Price> {
+    // the most specific prefix goes first
+    const PRICES: &[(&str, f64, f64)] = &[
+        ("gpt-4o-mini", 0.15, 0.6),
+        ("gpt-4o", 2.5, 10.0),
+        ("gpt-4.1-nano", 0.1, 0.4),
+        ("gpt-4.1-mini", 0.4, 1.6),
+        ("gpt-4.1", 2.0, 8.0),
+        ("gpt-4-turbo", 10.0, 30.0),
+        ("gpt-3.5-turbo", 0.5, 1.5),
+    ];
+    if let Some(price) = prices.get(model) {
+        return Some(*price);
+    }
+    if backend != DetectorBackend::OpenAi {
+        return None;
+    }
+    PRICES
+        .iter()
+        .find(|(prefix, _, _)| model.starts_with(prefix))
+        .map(|(_, prompt, completion)| Price {
+            prompt: *prompt,
+            completion: *completion,
+        })
+}
+
+/// What is spent in the day and in the month, in USD.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Spending {
+    pub today: f64,
+    pub this_month: f64,
+}
+
+/// What the entries spent in the day and in the month of `now`, both in UTC.
+pub fn spending(entries: &[HistoryEntry], now: DateTime<Utc>) -> Spending {
+    let today = Utc.from_utc_datetime(&now.date_naive().and_time(NaiveTime::MIN));
+    let this_month = today.with_day(1).unwrap();
+    let mut spending = Spending::default();
+    for entry in entries {
+        if entry.checked_at >= this_month && entry.checked_at <= now {
+            spending.this_month += entry.cost;
+            if entry.checked_at >= today {
+                spending.today += entry.cost;
+            }
+        }
+    }
+    spending
+}
+
+/// The spending limits, checked against the costs recorded in the history.
+#[derive(Debug, Clone)]
+pub struct Budget {
+    history: History,
+    daily_limit: Option<f64>,
+    monthly_limit: Option<f64>,
+}
+
+impl Budget {
+    pub fn new(history: History, daily_limit: Option<f64>, monthly_limit: Option<f64>) -> Self {
+        Self {
+            history,
+            daily_limit,
+            monthly_limit,
+        }
+    }
+
+    /// Which limit is reached, if any.
+    pub async fn exhausted(&self) -> Result<Option<String>, Box<dyn Error>> {
+        let spending = spending(&self.history.load().await?, Utc::now());
+        Ok(exhausted(spending, self.daily_limit, self.monthly_limit))
+    }
+}
+
+fn exhausted(
+    spending: Spending,
+    daily_limit: Option<f64>,
+    monthly_limit: Option<f64>,
+) -> Option<String> {
+    for (period, spent, limit) in [
+        ("daily", spending.today, daily_limit),
+        ("monthly", spending.this_month, monthly_limit),
+    ] {
+        if let Some(limit) = limit.filter(|limit| spent >= *limit) {
+            return Some(format!(
+                "the {} spending limit of ${:.2} is reached, ${:.4} spent",
+                period, limit, spent
+            ));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::{test_entry, Outcome};
+
+    fn entry(checked_at: &str, cost: f64) -> HistoryEntry {
+        HistoryEntry {
+            cost,
+            ..test_entry("default", Outcome::Clean, checked_at.parse().unwrap())
+        }
+    }
+
+    #[test]
+    fn test_price() {
+        let usage = Usage {
+            prompt_tokens: 2_000_000,
+            completion_tokens: 500_000,
+        };
+        let mini = price(&BTreeMap::new(), DetectorBackend::OpenAi, "gpt-4o-mini").unwrap();
+        assert_eq!(mini.cost(usage), 0.6);
+        let gpt4o = price(
+            &BTreeMap::new(),
+            DetectorBackend::OpenAi,
+            "gpt-4o-2024-08-06",
+        );
+        assert_eq!(gpt4o.unwrap().prompt, 2.5);
+        assert!(price(&BTreeMap::new(), DetectorBackend::OpenAi, "o1").is_none());
+
+        let prices = BTreeMap::from([(
+            "qwen2.5:7b".to_string(),
+            Price {
+                prompt: 0.01,
+                completion: 0.02,
+            },
+        )]);
+        assert!(price(&prices, DetectorBackend::Ollama, "gpt-4o-mini").is_none());
+        let qwen = price(&prices, DetectorBackend::Ollama, "qwen2.5:7b").unwrap();
+        assert_eq!(qwen.cost(usage), 0.03);
+    }
+
+    #[test]
+    fn test_spending() {
+        let entries = vec![
+            entry("2025-02-28T23:00:00Z", 1.0),
+            entry("2025-03-01T00:00:00Z", 0.5),
+            entry("2025-03-14T23:59:59Z", 0.25),
+            entry("2025-03-15T00:00:00Z", 0.125),
+            entry("2025-03-15T09:00:00Z", 0.0625),
+            // not yet, e.g. recorded by a host with a skewed clock
+            entry("2025-03-15T20:00:00Z", 2.0),
+        ];
+        let now = "2025-03-15T10:00:00Z".parse().unwrap();
+        let spent = spending(&entries, now);
+        assert_eq!(
+            spent,
+            Spending {
+                today: 0.1875,
+                this_month: 0.9375,
+            }
+        );
+
+        assert_eq!(exhausted(spent, None, None), None);
+        assert_eq!(exhausted(spent, Some(0.2), Some(1.0)), None);
+        assert_eq!(
+            exhausted(spent, Some(0.1875), Some(1.0)).unwrap(),
+            "the daily spending limit of $0.19 is reached, $0.1875 spent"
+        );
+        assert!(exhausted(spent, None, Some(0.9))
+            .unwrap()
+            .starts_with("the monthly"));
+    }
+}
@@ -0,0 +1,148 @@
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{Aead, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use argon2::Argon2;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::path::Path;
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const ENVELOPE_VERSION: u32 = 1;
+
+/// The secret from which the session encryption key is derived.
+#[derive(Clone)]
+pub struct SecretKey {
+    material: Vec<u8>,
+}
+
+impl SecretKey {
+    pub fn from_passphrase(passphrase: impl AsRef<str>) -> Self {
+        Self {
+            material: passphrase.as_ref().as_bytes().to_vec(),
+        }
+    }
+
+    /// Use the content of a key file as the secret. Trailing white spaces are ignored, so that
+    /// both raw random bytes and text keys (e.g. from `openssl rand -base64 32`) can be used.
+    pub async fn from_key_file(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        let mut material = tokio::fs::read(path.as_ref()).await.map_err(|err| {
+            format!(
+                "failed to read session key file {}: {}",
+                path.as_ref().display(),
+                err
+            )
+        })?;
+        while material.last().is_some_and(|c| c.is_ascii_whitespace()) {
+            material.pop();
+        }
+        if material.is_empty() {
+            return Err(format!("session key file {} is empty", path.as_ref().display()).into());
+        }
+        Ok(Self { material })
+    }
+
+    fn derive(&self, salt: &[u8]) -> Result<Key<Aes256Gcm>, Box<dyn Error>> {
+        let mut key = Key::<Aes256Gcm>::default();
+        Argon2::default()
+            .hash_password_into(&self.material, salt, &mut key)
+            .map_err(|err| format!("failed to derive session key: {}", err))?;
+        Ok(key)
+    }
+}
+
+impl std::fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SecretKey(<redacted>)")
+    }
+}
+
+/// Serialized form of an encrypted payload. The salt is stored alongside the cipher text so the
+/// key can be re-derived from the secret, and the AES-GCM tag authenticates both of them.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Envelope {
+    version: u32,
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+impl Envelope {
+    pub fn seal(key: &SecretKey, plaintext: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+
+        let cipher = Aes256Gcm::new(&key.derive(&salt)?);
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(&nonce), plaintext)
+            .map_err(|_| "failed to encrypt")?;
+        Ok(Self {
+            version: ENVELOPE_VERSION,
+            salt: BASE64_STANDARD.encode(salt),
+            nonce: BASE64_STANDARD.encode(nonce),
+            ciphertext: BASE64_STANDARD.encode(ciphertext),
+        })
+    }
+
+    pub fn open(&self, key: &SecretKey) -> Result<Vec<u8>, Box<dyn Error>> {
+        if self.version != ENVELOPE_VERSION {
+            return Err(format!("unsupported envelope version {}", self.version).into());
+        }
+        let salt = BASE64_STANDARD.decode(&self.salt)?;
+        let nonce = BASE64_STANDARD.decode(&self.nonce)?;
+        if nonce.len() != NONCE_LEN {
+            return Err("malformed nonce".into());
+        }
+        let ciphertext = BASE64_STANDARD.decode(&self.ciphertext)?;
+
+        let cipher = Aes256Gcm::new(&key.derive(&salt)?);
+        cipher
+            .decrypt(Nonce::from_slice(&nonce), ciphertext.as_slice())
+            .map_err(|_| "wrong key or corrupted data".into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_and_open() {
+        let key = SecretKey::from_passphrase("correct horse battery staple");
+        let envelope = Envelope::seal(&key, b"secret data").unwrap();
+        assert_eq!(envelope.open(&key).unwrap(), b"secret data");
+    }
+
+    #[test]
+    fn test_open_with_wrong_key() {
+        let key = SecretKey::from_passphrase("correct horse battery staple");
+        let envelope = Envelope::seal(&key, b"secret data").unwrap();
+        let wrong = SecretKey::from_passphrase("wrong");
+        assert!(envelope.open(&wrong).is_err());
+    }
+
+    #[test]
+    fn test_tampered_envelope() {
+        let key = SecretKey::from_passphrase("correct horse battery staple");
+        let mut envelope = Envelope::seal(&key, b"secret data").unwrap();
+        let mut ciphertext = BASE64_STANDARD.decode(&envelope.ciphertext).unwrap();
+        ciphertext[0] ^= 1;
+        envelope.ciphertext = BASE64_STANDARD.encode(ciphertext);
+        assert!(envelope.open(&key).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_key_file_ignores_trailing_newline() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("key");
+        tokio::fs::write(&path, "passphrase\n").await.unwrap();
+        let from_file = SecretKey::from_key_file(&path).await.unwrap();
+        let envelope = Envelope::seal(&from_file, b"data").unwrap();
+        assert!(envelope
+            .open(&SecretKey::from_passphrase("passphrase"))
+            .is_ok());
+    }
+}
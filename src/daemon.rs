@@ -0,0 +1,436 @@
+use crate::api;
+use crate::bot::Bot;
+use crate::config::{Config, WhenExhausted};
+use crate::metrics::{FailureKind, METRICS};
+use crate::post;
+use crate::server::Probe;
+use async_trait::async_trait;
+use atrium_api::app::bsky::feed::post::RecordData;
+use atrium_api::app::bsky::notification::list_notifications::Notification;
+use atrium_api::app::bsky::richtext::facet::MainFeaturesItem;
+use atrium_api::com::atproto::repo::strong_ref;
+use atrium_api::types::string::{Datetime, Did};
+use atrium_api::types::Union;
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{error, info, info_span, warn, Instrument};
+
+/// Watches the mentions of all bots and replies to the summons.
+pub struct Daemon {
+    bots: Vec<Bot>,
+    poll_interval: Duration,
+    notification_limit: u8,
+    dry_run: bool,
+    summary_keywords: Vec<String>,
+    when_exhausted: WhenExhausted,
+    /// when the mentions of each bot were last polled successfully
+    last_polls: Mutex<HashMap<Did, Instant>>,
+    /// how many times each failing summon has been tried
+    attempts: Mutex<HashMap<String, u32>>,
+}
+
+/// How many times a summon is tried before giving up on it, so that a summon which always fails,
+/// e.g. in a deleted thread, doesn't hold back the others.
+const MAX_ATTEMPTS: u32 = 3;
+
+impl Daemon {
+    pub fn new(bots: Vec<Bot>, config: &Config) -> Self {
+        Self {
+            bots,
+            poll_interval: Duration::from_secs(config.daemon.poll_interval),
+            notification_limit: config.daemon.notification_limit,
+            dry_run: config.dry_run,
+            summary_keywords: config.daemon.summary_keywords.clone(),
+            when_exhausted: config.cost.when_exhausted,
+            last_polls: Mutex::new(HashMap::new()),
+            attempts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// A poll older than this means the daemon is stuck or cannot reach the PDS.
+    fn poll_deadline(&self) -> Duration {
+        self.poll_interval * 3 + Duration::from_secs(60)
+    }
+
+    pub async fn run(&self) -> Result<(), Box<dyn Error>> {
+        info!(
+            "watching mentions of {}",
+            self.bots
+                .iter()
+                .map(|b| b.persona.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        loop {
+            for bot in &self.bots {
+                let span = info_span!("poll", persona = %bot.persona.name);
+                match self.poll(bot).instrument(span.clone()).await {
+                    Ok(()) => {
+                        let mut last_polls = self.last_polls.lock().unwrap();
+                        last_polls.insert(bot.did.clone(), Instant::now());
+                    }
+                    Err(err) => {
+                        METRICS.failure(FailureKind::Poll);
+                        span.in_scope(|| error!("failed to poll mentions: {}", err));
+                    }
+                }
+            }
+            tokio::time::sleep(self.poll_interval).await;
+        }
+    }
+
+    async fn poll(&self, bot: &Bot) -> Result<(), Box<dyn Error>> {
+        if self.when_exhausted == WhenExhausted::Pause {
+            if let Some(reason) = bot.exhausted().await? {
+                // the summons stay unread, to be answered once the budget renews
+                info!("{}, pausing", reason);
+                return Ok(());
+            }
+        }
+        let seen_at = Datetime::now();
+        let mentions = api::list_unread_mentions(&bot.agent, self.notification_limit).await?;
+        // handle the earliest summon first, and leave a failed one unread along with the later
+        // ones, so that it's retried on the next poll
+        let mut handled_at = None;
+        for notification in mentions.iter().rev() {
+            let uri = notification.uri.as_str();
+            if self.should_handle(bot, notification) && !self.handle(bot, notification).await {
+                if should_retry(&mut self.attempts.lock().unwrap(), uri) {
+                    warn!("leaving {} unread to retry it", uri);
+                    return match handled_at {
+                        Some(handled_at) => api::update_seen(&bot.agent, handled_at).await,
+                        None => Ok(()),
+                    };
+                }
+                error!("giving up on {} after {} attempts", uri, MAX_ATTEMPTS);
+            }
+            self.attempts.lock().unwrap().remove(uri);
+            handled_at = Some(notification.indexed_at.clone());
+        }
+        api::update_seen(&bot.agent, seen_at).await
+    }
+
+    fn should_handle(&self, bot: &Bot, notification: &Notification) -> bool {
+        let author = &notification.author;
+        if self.bots.iter().any(|b| b.did == author.did) {
+            return false;
+        }
+        let dids: Vec<Did> = self.bots.iter().map(|b| b.did.clone()).collect();
+        let record = post::parse_record_from_unknown(&notification.record);
+        if let Some(target) = record.and_then(|r| route(&r, &dids)) {
+            if target != bot.did {
+                info!("{} is handled by another persona", notification.uri);
+                return false;
+            }
+        }
+        if !bot.persona.allows(&author.did, author.handle.as_str()) {
+            info!("{} is not allowed to summon", author.handle.as_str());
+            return false;
+        }
+        true
+    }
+
+    /// Answer the summon. Returns whether it was handled, which is false if it failed in a way
+    /// that may pass when retried.
+    async fn handle(&self, bot: &Bot, notification: &Notification) -> bool {
+        let span = bot.check_span(&notification.uri, Some(&notification.author.did));
+        async {
+            METRICS.summons.inc();
+            info!("summoned by {}", notification.author.handle.as_str());
+            let record = post::parse_record_from_unknown(&notification.record);
+            if self.when_exhausted == WhenExhausted::Reply {
+                match bot.exhausted().await {
+                    Ok(None) => {}
+                    Ok(Some(reason)) => {
+                        warn!("{}", reason);
+                        return self
+                            .reply_exhausted(bot, notification, record.as_ref(), &reason)
+                            .await;
+                    }
+                    Err(err) => {
+                        error!("failed to read the spending: {}", err);
+                        return false;
+                    }
+                }
+            }
+            if record.is_some_and(|r| wants_summary(&r, &self.summary_keywords)) {
+                return self.summarize(bot, notification).await;
+            }
+            let (thread, posts) = match bot.fetch(&notification.uri).await {
+                Ok(fetched) => fetched,
+                Err(err) => {
+                    METRICS.failure(FailureKind::FetchThread);
+                    error!("failed to fetch the thread: {}", err);
+                    return false;
+                }
+            };
+            // no reply when the detector fails, as it would wrongly say nobody is found
+            let result = match bot.check_fetched(&notification.uri, &thread, &posts).await {
+                Ok(result) => result,
+                Err(err) => {
+                    METRICS.failure(FailureKind::Detect);
+                    error!("failed to check: {}", err);
+                    return false;
+                }
+            };
+            if let Err(err) = bot.publish(&result, self.dry_run).await {
+                METRICS.failure(FailureKind::Reply);
+                error!("failed to reply: {}", err);
+                return false;
+            }
+            true
+        }
+        .instrument(span)
+        .await
+    }
+
+    async fn reply_exhausted(
+        &self,
+        bot: &Bot,
+        notification: &Notification,
+        record: Option<&RecordData>,
+        reason: &str,
+    ) -> bool {
+        let parent = strong_ref::Main::from(strong_ref::MainData {
+            cid: notification.cid.clone(),
+            uri: notification.uri.clone(),
+        });
+        // the summon may start a thread of its own
+        let root = record
+            .and_then(|r| r.reply.as_ref())
+            .map(|reply| reply.root.clone())
+            .unwrap_or_else(|| parent.clone());
+        if let Err(err) = bot
+            .publish_exhausted(parent, root, reason, self.dry_run)
+            .await
+        {
+            METRICS.failure(FailureKind::Reply);
+            error!("failed to reply: {}", err);
+            return false;
+        }
+        true
+    }
+
+    async fn summarize(&self, bot: &Bot, notification: &Notification) -> bool {
+        let (thread, posts) = match bot.fetch(&notification.uri).await {
+            Ok(fetched) => fetched,
+            Err(err) => {
+                METRICS.failure(FailureKind::FetchThread);
+                error!("failed to fetch the thread: {}", err);
+                return false;
+            }
+        };
+        let summary = match bot
+            .summarize_fetched(&notification.uri, &thread, &posts)
+            .await
+        {
+            Ok(summary) => summary,
+            Err(err) => {
+                METRICS.failure(FailureKind::Detect);
+                error!("failed to summarize: {}", err);
+                return false;
+            }
+        };
+        if let Err(err) = bot.publish_summary(&summary, self.dry_run).await {
+            METRICS.failure(FailureKind::Reply);
+            error!("failed to reply: {}", err);
+            return false;
+        }
+        true
+    }
+}
+
+#[async_trait]
+impl Probe for Daemon {
+    async fn ready(&self) -> Result<(), Vec<String>> {
+        let mut problems = Vec::new();
+        for bot in &self.bots {
+            let name = &bot.persona.name;
+            if let Err(err) = api::check_session(&bot.agent).await {
+                problems.push(format!("{}: invalid session: {}", name, err));
+            }
+            let last_poll = self.last_polls.lock().unwrap().get(&bot.did).copied();
+            if let Some(problem) = stale_poll(last_poll, self.poll_deadline()) {
+                problems.push(format!("{}: {}", name, problem));
+            }
+            if let Err(err) = bot.ping().await {
+                problems.push(format!("{}: detector: {}", name, err));
+            }
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
+}
+
+fn stale_poll(last_poll: Option<Instant>, deadline: Duration) -> Option<String> {
+    match last_poll {
+        None => Some("no successful poll yet".to_string()),
+        Some(at) if at.elapsed() > deadline => Some(format!(
+            "last successful poll was {}s ago",
+            at.elapsed().as_secs()
+        )),
+        Some(_) => None,
+    }
+}
+
+/// Count a failed attempt at the summon, returning whether to try it again.
+fn should_retry(attempts: &mut HashMap<String, u32>, uri: &str) -> bool {
+    let tried = attempts.entry(uri.to_string()).or_default();
+    *tried += 1;
+    if *tried < MAX_ATTEMPTS {
+        return true;
+    }
+    attempts.remove(uri);
+    false
+}
+
+/// Find the first mentioned account among the candidates, so that a post mentioning several bots
+/// is answered only once.
+pub fn route(record: &RecordData, candidates: &[Did]) -> Option<Did> {
+    let mut mentions: Vec<(usize, &Did)> = record
+        .facets
+        .iter()
+        .flatten()
+        .flat_map(|facet| {
+            facet.features.iter().filter_map(|feature| match feature {
+                Union::Refs(MainFeaturesItem::Mention(mention)) => {
+                    Some((facet.index.byte_start, &mention.did))
+                }
+                _ => None,
+            })
+        })
+        .filter(|(_, did)| candidates.contains(did))
+        .collect();
+    mentions.sort_by_key(|(start, _)| *start);
+    mentions.first().map(|(_, did)| (*did).clone())
+}
+
+/// Whether the summoning post asks for a summary, going by its text other than the mentions.
+pub fn wants_summary(record: &RecordData, keywords: &[String]) -> bool {
+    let mut text = record.text.clone();
+    let mut mentions: Vec<(usize, usize)> = record
+        .facets
+        .iter()
+        .flatten()
+        .filter(|facet| {
+            facet
+                .features
+                .iter()
+                .any(|feature| matches!(feature, Union::Refs(MainFeaturesItem::Mention(_))))
+        })
+        .map(|facet| (facet.index.byte_start, facet.index.byte_end))
+        .collect();
+    // from the end, so that the earlier ranges stay valid
+    mentions.sort();
+    for (start, end) in mentions.into_iter().rev() {
+        if start <= end && text.get(start..end).is_some() {
+            text.replace_range(start..end, " ");
+        }
+    }
+    let text = text.to_lowercase();
+    keywords
+        .iter()
+        .any(|keyword| text.contains(&keyword.to_lowercase()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DaemonConfig;
+    use std::str::FromStr;
+
+    fn mention_record(mentions: &[(usize, &str)]) -> RecordData {
+        let facets = mentions
+            .iter()
+            .map(|(start, did)| {
+                format!(
+                    r#"{{
+                        "index": {{"byteStart": {}, "byteEnd": {}}},
+                        "features": [{{"$type": "app.bsky.richtext.facet#mention", "did": "{}"}}]
+                    }}"#,
+                    start,
+                    start + 4,
+                    did
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        serde_json::from_str(&format!(
+            r#"{{"text": "@zh @en @someone", "createdAt": "2024-11-08T20:01:00.000Z", "facets": [{}]}}"#,
+            facets
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_stale_poll() {
+        let deadline = Duration::from_secs(60);
+        assert!(stale_poll(None, deadline).is_some());
+        assert!(stale_poll(Some(Instant::now()), deadline).is_none());
+        let long_ago = Instant::now() - Duration::from_secs(90);
+        assert_eq!(
+            stale_poll(Some(long_ago), deadline).unwrap(),
+            "last successful poll was 90s ago"
+        );
+    }
+
+    #[test]
+    fn test_should_retry() {
+        let mut attempts = HashMap::new();
+        assert!(should_retry(&mut attempts, "at://a"));
+        assert!(should_retry(&mut attempts, "at://b"));
+        assert!(should_retry(&mut attempts, "at://a"));
+        assert!(!should_retry(&mut attempts, "at://a"));
+        assert_eq!(attempts.get("at://b"), Some(&1));
+        assert!(!attempts.contains_key("at://a"));
+        // tried afresh after giving up, e.g. when mentioned again
+        assert!(should_retry(&mut attempts, "at://a"));
+    }
+
+    #[test]
+    fn test_route() {
+        let zh = Did::from_str("did:plc:zh").unwrap();
+        let en = Did::from_str("did:plc:en").unwrap();
+        let candidates = vec![zh.clone(), en.clone()];
+
+        let record = mention_record(&[(4, "did:plc:en"), (0, "did:plc:zh")]);
+        assert_eq!(route(&record, &candidates), Some(zh.clone()));
+
+        let record = mention_record(&[(0, "did:plc:someone"), (4, "did:plc:en")]);
+        assert_eq!(route(&record, &candidates), Some(en));
+
+        let record = mention_record(&[(0, "did:plc:someone")]);
+        assert_eq!(route(&record, &candidates), None);
+
+        let record = mention_record(&[]);
+        assert_eq!(route(&record, &candidates), None);
+    }
+
+    #[test]
+    fn test_wants_summary() {
+        let keywords = DaemonConfig::default().summary_keywords;
+        let record = |text: &str| -> RecordData {
+            serde_json::from_str(&format!(
+                r#"{{"text": "{}", "createdAt": "2024-11-08T20:01:00.000Z", "facets": [{{
+                    "index": {{"byteStart": 0, "byteEnd": 11}},
+                    "features": [{{"$type": "app.bsky.richtext.facet#mention", "did": "did:plc:zh"}}]
+                }}]}}"#,
+                text
+            ))
+            .unwrap()
+        };
+        assert!(wants_summary(
+            &record("@summarybot Summary please"),
+            &keywords
+        ));
+        assert!(wants_summary(&record("@summarybot 总结一下"), &keywords));
+        // the handle of the bot does not count
+        assert!(!wants_summary(&record("@summarybot who did it"), &keywords));
+        assert!(!wants_summary(&record("@summarybot summary"), &[]));
+    }
+}
@@ -4,7 +4,7 @@ use atrium_api::app::bsky::richtext::facet;
 use atrium_api::app::bsky::richtext::facet::{
     ByteSlice, ByteSliceData, MainFeaturesItem, Mention, MentionData,
 };
-use atrium_api::types::string::Language;
+use atrium_api::types::string::{Did, Language};
 use atrium_api::types::Union;
 use atrium_api::{
     app::bsky::feed::post::{RecordData, ReplyRef, ReplyRefData},
@@ -12,52 +12,322 @@ use atrium_api::{
     types::string::Datetime,
 };
 use ellipse::Ellipse;
+use serde::{Deserialize, Serialize};
 
+use crate::detector::{Derailment, DriftPoint, Explanation, Summary};
 use crate::post::Post;
+use crate::util;
+use chrono::{DateTime, Utc};
+
+/// how many characters a post may have
+pub const MAX_POST_LENGTH: usize = 300;
+/// the explanation is left out if a sentence of it gets shorter than this
+const MIN_SENTENCE_LENGTH: usize = 12;
+/// how many characters of the topic after a derail point are shown
+const DERAILED_TOPIC_LENGTH: usize = 40;
+
+/// The wording of the replies of a persona.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReplyTemplate {
+    /// text before the mention of the sidetracker
+    pub culprit: String,
+    /// text before the excerpt of the sidetracking post
+    pub evidence: String,
+    /// the whole reply when there is no sidetracker
+    pub not_found: String,
+    /// languages of the reply
+    pub langs: Vec<String>,
+    /// how many characters of the sidetracking post are quoted
+    pub excerpt_length: usize,
+    /// text before the topic of the thread, in explain mode
+    pub topic: String,
+    /// text before why the sidetracker derails the thread, in explain mode
+    pub reason: String,
+    /// text before where the thread went, in a summary
+    pub direction: String,
+    /// text before the list of the derailing posts, when there are more than one
+    pub culprits: String,
+    /// text before the mention of whoever brought the thread back on track, whose post is quoted
+    pub rescuer: String,
+    /// the line telling how soon the thread derailed, `{}` being how long after the root post,
+    /// e.g. `3m` or `2h`. Left out if empty or the times are unknown.
+    pub delay: String,
+    /// the whole reply when a spending limit is reached. No reply is made if empty.
+    pub exhausted: String,
+}
+
+impl Default for ReplyTemplate {
+    fn default() -> Self {
+        Self {
+            culprit: "最有可能的歪楼犯：".to_string(),
+            evidence: "罪证：".to_string(),
+            not_found: "太好了，没有找到歪楼犯".to_string(),
+            langs: vec!["zh-CN".to_string(), "en-US".to_string()],
+            excerpt_length: 20,
+            topic: "原帖在聊：".to_string(),
+            reason: "歪在：".to_string(),
+            direction: "现在聊到：".to_string(),
+            culprits: "歪楼犯们：".to_string(),
+            rescuer: "把楼掰回来的好人：".to_string(),
+            delay: "开楼{}后就歪了".to_string(),
+            exhausted: "算力预算用完了，晚点再来找我吧".to_string(),
+        }
+    }
+}
+
+impl ReplyTemplate {
+    fn languages(&self) -> Vec<Language> {
+        self.langs
+            .iter()
+            .filter_map(|lang| Language::new(lang.clone()).ok())
+            .collect()
+    }
+
+    /// A reply of only the text to the post, e.g. when the thread cannot be checked for now.
+    pub(crate) fn notice(
+        &self,
+        text: &str,
+        parent: strong_ref::Main,
+        root: strong_ref::Main,
+    ) -> RecordData {
+        RecordData {
+            created_at: Datetime::now(),
+            entities: None,
+            facets: None,
+            labels: None,
+            langs: Some(self.languages()),
+            reply: Some(ReplyRef::from(ReplyRefData { parent, root })),
+            tags: None,
+            text: fit_sentence(text, MAX_POST_LENGTH),
+            embed: None,
+        }
+    }
+}
+
+/// Fit a sentence into `length` characters.
+fn fit_sentence(text: &str, length: usize) -> String {
+    if text.chars().count() <= length {
+        return text.to_string();
+    }
+    text.truncate_ellipse(length.saturating_sub(3)).to_string()
+}
+
+/// The lines explaining the verdict, shortened to fit into `room` characters, or nothing if they
+/// do not fit.
+fn explanation_lines(template: &ReplyTemplate, explanation: &Explanation, room: usize) -> String {
+    let mut lines = vec![(template.topic.as_str(), explanation.topic.as_str())];
+    if !explanation.reason.is_empty() {
+        lines.push((template.reason.as_str(), explanation.reason.as_str()));
+    }
+    fit_lines(&lines, room)
+}
+
+/// The `(label, sentence)` lines, with the sentences shortened to fit into `room` characters, or
+/// nothing if they do not fit.
+fn fit_lines(lines: &[(&str, &str)], room: usize) -> String {
+    // the labels and the line breaks
+    let fixed: usize = lines
+        .iter()
+        .map(|(label, _)| label.chars().count() + 1)
+        .sum();
+    let Some(mut room) = room.checked_sub(fixed) else {
+        return String::new();
+    };
+    let mut text = String::new();
+    for (i, (label, sentence)) in lines.iter().enumerate() {
+        // a short sentence leaves its share to the next one
+        let share = room / (lines.len() - i);
+        if share < MIN_SENTENCE_LENGTH {
+            return String::new();
+        }
+        let sentence = fit_sentence(sentence, share);
+        room -= sentence.chars().count();
+        text.push_str(label);
+        text.push_str(&sentence);
+        text.push('\n');
+    }
+    text
+}
+
+/// A post of the checked thread and how related it is to the topic.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TimelineEntry {
+    pub idx: u32,
+    pub handle: String,
+    pub uri: String,
+    pub text: String,
+    pub posted_at: Option<DateTime<Utc>>,
+    /// from 0 for off topic to 1 for on topic
+    pub relatedness: f64,
+}
+
+impl TimelineEntry {
+    /// Pair the posts with their points on the drift curve.
+    pub fn from_drift<'a>(
+        posts: impl IntoIterator<Item = &'a Post>,
+        drift: &[DriftPoint],
+    ) -> Vec<Self> {
+        posts
+            .into_iter()
+            .filter_map(|post| {
+                let point = drift.iter().find(|p| p.idx == post.idx)?;
+                Some(Self {
+                    idx: post.idx,
+                    handle: post.handle.clone(),
+                    uri: post.uri.clone(),
+                    text: post.text.clone(),
+                    posted_at: post.posted_at(),
+                    relatedness: point.relatedness,
+                })
+            })
+            .collect()
+    }
+}
+
+fn mention_facet(did: &Did, start: usize, end: usize) -> facet::Main {
+    let mention =
+        MainFeaturesItem::Mention(Box::from(Mention::from(MentionData { did: did.clone() })));
+    facet::Main::from(facet::MainData {
+        features: vec![Union::Refs(mention)],
+        index: ByteSlice::from(ByteSliceData {
+            byte_start: start,
+            byte_end: end,
+        }),
+    })
+}
 
 #[derive(Debug, PartialEq)]
 pub(crate) struct SideTracker {
-    /// The sidetracking post
-    post: Option<Post>,
+    /// The posts derailing the thread in order, the first one is the sidetracker
+    culprits: Vec<Derailment>,
     /// The root post of the thread
     root: Post,
     /// The leaf post from where this checking is triggered
     entrance: Post,
+    /// The posts of the thread with their relatedness to the topic
+    timeline: Vec<TimelineEntry>,
+    /// The topic and why the post derails it, in explain mode
+    explanation: Option<Explanation>,
+    /// The post bringing the thread back on track
+    rescuer: Option<Post>,
 }
 
 impl SideTracker {
+    #[cfg(test)]
     pub(crate) fn new(post: Option<Post>, root: Post, entrance: Post) -> SideTracker {
+        Self::from_derailments(
+            post.into_iter().map(Derailment::from).collect(),
+            root,
+            entrance,
+        )
+    }
+
+    pub(crate) fn from_derailments(
+        culprits: Vec<Derailment>,
+        root: Post,
+        entrance: Post,
+    ) -> SideTracker {
         SideTracker {
-            post,
+            culprits,
             root,
             entrance,
+            timeline: Vec::new(),
+            explanation: None,
+            rescuer: None,
         }
     }
 
-    pub(crate) fn build_reply(&self) -> RecordData {
+    pub(crate) fn with_timeline(self, timeline: Vec<TimelineEntry>) -> SideTracker {
+        SideTracker { timeline, ..self }
+    }
+
+    pub(crate) fn with_explanation(self, explanation: Option<Explanation>) -> SideTracker {
+        SideTracker {
+            explanation,
+            ..self
+        }
+    }
+
+    pub(crate) fn with_rescuer(self, rescuer: Option<Post>) -> SideTracker {
+        SideTracker { rescuer, ..self }
+    }
+
+    pub(crate) fn rescuer(&self) -> Option<&Post> {
+        self.rescuer.as_ref()
+    }
+
+    pub(crate) fn explanation(&self) -> Option<&Explanation> {
+        self.explanation.as_ref()
+    }
+
+    pub(crate) fn sidetracker(&self) -> Option<&Post> {
+        self.culprits.first().map(|d| &d.post)
+    }
+
+    pub(crate) fn culprits(&self) -> &[Derailment] {
+        &self.culprits
+    }
+
+    pub(crate) fn timeline(&self) -> &[TimelineEntry] {
+        &self.timeline
+    }
+
+    /// The verdict as JSON, for the dry run output.
+    pub(crate) fn verdict_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "sidetracker": self.sidetracker().map(|p| p.idx),
+            "derailments": self
+                .culprits
+                .iter()
+                .map(|d| serde_json::json!({ "idx": d.post.idx, "topic": d.topic }))
+                .collect::<Vec<_>>(),
+            "root": self.root.uri,
+            "rescuer": self.rescuer.as_ref().map(|p| p.idx),
+            "explanation": self.explanation,
+            "posts": self.timeline,
+        })
+    }
+
+    pub(crate) fn build_reply(&self, template: &ReplyTemplate) -> RecordData {
         let mut facets: Vec<facet::Main> = Vec::new();
         let mut embed = None;
-        let text = if let Some(ref p) = self.post {
-            let mut text = "最有可能的歪楼犯：".to_string();
+        let text = if self.culprits.len() > 1 {
+            embed = Some(Union::Refs((&self.culprits[0].post).into()));
+            self.culprits_text(template, &mut facets)
+        } else if let Some(p) = self.sidetracker() {
+            let mut text = template.culprit.clone();
             {
                 let mention_start = text.len();
-                text.push_str("@");
+                text.push('@');
                 text.push_str(&p.handle);
                 let mention_end = text.len();
-                text.push_str("\n");
-                let mention = MainFeaturesItem::Mention(Box::from(Mention::from(MentionData {
-                    did: p.did.clone(),
-                })));
-                facets.push(facet::Main::from(facet::MainData {
-                    features: vec![Union::Refs(mention)],
-                    index: ByteSlice::from(ByteSliceData {
-                        byte_start: mention_start,
-                        byte_end: mention_end,
-                    }),
-                }));
+                text.push('\n');
+                facets.push(mention_facet(&p.did, mention_start, mention_end));
             }
 
-            text.push_str(format!("罪证：{}\n", p.text.as_str().truncate_ellipse(20)).as_str());
+            text.push_str(&template.evidence);
+            text.push_str(
+                p.text
+                    .as_str()
+                    .truncate_ellipse(template.excerpt_length)
+                    .as_ref(),
+            );
+            text.push('\n');
+            if let Some(delay) = self.delay_line(template, p) {
+                text.push_str(&delay);
+                text.push('\n');
+            }
+            self.push_rescuer(&mut text, template, &mut facets);
+
+            if let Some(ref explanation) = self.explanation {
+                let used = text.chars().count() + p.get_share_uri().chars().count();
+                text.push_str(&explanation_lines(
+                    template,
+                    explanation,
+                    MAX_POST_LENGTH.saturating_sub(used),
+                ));
+            }
 
             {
                 let link_start = text.len();
@@ -78,18 +348,28 @@ impl SideTracker {
             embed = Some(Union::Refs(p.into()));
             text
         } else {
-            "太好了，没有找到歪楼犯".to_string()
+            let mut text = template.not_found.clone();
+            if let Some(ref explanation) = self.explanation {
+                text.push('\n');
+                let room = MAX_POST_LENGTH.saturating_sub(text.chars().count());
+                text.push_str(&explanation_lines(template, explanation, room));
+            }
+            text.trim_end().to_string()
         };
+        if let Some(ref rescuer) = self.rescuer {
+            embed = Some(Union::Refs(rescuer.into()));
+        }
 
         RecordData {
             created_at: Datetime::now(),
             entities: None,
-            facets: if facets.len() > 0 { Some(facets) } else { None },
+            facets: if !facets.is_empty() {
+                Some(facets)
+            } else {
+                None
+            },
             labels: None,
-            langs: Some(vec![
-                Language::new("zh-CN".to_string()).unwrap(),
-                Language::new("en-US".to_string()).unwrap(),
-            ]),
+            langs: Some(template.languages()),
             reply: Some(ReplyRef::from(Into::<ReplyRefData>::into(self))),
             tags: None,
             text,
@@ -98,6 +378,124 @@ impl SideTracker {
     }
 }
 
+/// What a thread was about and where it went, replied with the root post quoted.
+#[derive(Debug, PartialEq)]
+pub(crate) struct ThreadSummary {
+    summary: Summary,
+    /// The root post of the thread
+    root: Post,
+    /// The leaf post from where the summary is asked
+    entrance: Post,
+}
+
+impl ThreadSummary {
+    pub(crate) fn new(summary: Summary, root: Post, entrance: Post) -> ThreadSummary {
+        ThreadSummary {
+            summary,
+            root,
+            entrance,
+        }
+    }
+
+    /// The summary as JSON, for the dry run output.
+    pub(crate) fn summary_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "root": self.root.uri,
+            "topic": self.summary.topic,
+            "direction": self.summary.direction,
+        })
+    }
+
+    pub(crate) fn build_reply(&self, template: &ReplyTemplate) -> RecordData {
+        let text = fit_lines(
+            &[
+                (template.topic.as_str(), self.summary.topic.as_str()),
+                (template.direction.as_str(), self.summary.direction.as_str()),
+            ],
+            MAX_POST_LENGTH,
+        );
+        RecordData {
+            created_at: Datetime::now(),
+            entities: None,
+            facets: None,
+            labels: None,
+            langs: Some(template.languages()),
+            reply: Some(ReplyRef::from(Into::<ReplyRefData>::into(self))),
+            tags: None,
+            text: text.trim_end().to_string(),
+            embed: Some(Union::Refs((&self.root).into())),
+        }
+    }
+}
+
+impl SideTracker {
+    /// How long after the root post the sidetracker derailed the thread, if known.
+    fn delay_line(&self, template: &ReplyTemplate, sidetracker: &Post) -> Option<String> {
+        if template.delay.is_empty() {
+            return None;
+        }
+        let delay = sidetracker.posted_at()? - self.root.posted_at()?;
+        Some(template.delay.replace("{}", &util::format_duration(delay)))
+    }
+
+    /// Credit whoever brought the thread back on track, if anyone.
+    fn push_rescuer(
+        &self,
+        text: &mut String,
+        template: &ReplyTemplate,
+        facets: &mut Vec<facet::Main>,
+    ) {
+        let Some(ref rescuer) = self.rescuer else {
+            return;
+        };
+        text.push_str(&template.rescuer);
+        let mention_start = text.len();
+        text.push('@');
+        text.push_str(&rescuer.handle);
+        facets.push(mention_facet(&rescuer.did, mention_start, text.len()));
+        text.push('\n');
+    }
+
+    /// Name the derailing posts in order with what the thread drifted to, as many as fit.
+    fn culprits_text(&self, template: &ReplyTemplate, facets: &mut Vec<facet::Main>) -> String {
+        let mut text = template.culprits.clone();
+        text.push('\n');
+        for (n, culprit) in self.culprits.iter().enumerate() {
+            let number = format!("{}. ", n + 1);
+            let topic = culprit
+                .topic
+                .as_deref()
+                .map(|topic| format!(" → {}", fit_sentence(topic, DERAILED_TOPIC_LENGTH)))
+                .unwrap_or_default();
+            let length = number.chars().count()
+                + culprit.post.handle.chars().count()
+                + topic.chars().count()
+                + 2;
+            if text.chars().count() + length > MAX_POST_LENGTH {
+                break;
+            }
+            text.push_str(&number);
+            let mention_start = text.len();
+            text.push('@');
+            text.push_str(&culprit.post.handle);
+            facets.push(mention_facet(&culprit.post.did, mention_start, text.len()));
+            text.push_str(&topic);
+            text.push('\n');
+        }
+        if let Some(ref rescuer) = self.rescuer {
+            let length = template.rescuer.chars().count() + rescuer.handle.chars().count() + 2;
+            if text.chars().count() + length <= MAX_POST_LENGTH {
+                self.push_rescuer(&mut text, template, facets);
+            }
+        }
+        if let Some(ref explanation) = self.explanation {
+            let room = MAX_POST_LENGTH.saturating_sub(text.chars().count());
+            text.push_str(&explanation_lines(template, explanation, room));
+        }
+        text.trim_end().to_string()
+    }
+}
+
 impl From<&Post> for strong_ref::MainData {
     fn from(value: &Post) -> Self {
         Self {
@@ -131,9 +529,19 @@ impl From<&SideTracker> for ReplyRefData {
     }
 }
 
+impl From<&ThreadSummary> for ReplyRefData {
+    fn from(value: &ThreadSummary) -> Self {
+        Self {
+            parent: (&value.entrance).into(),
+            root: (&value.root).into(),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::post::test_post;
     use atrium_api::types::string::{Cid, Did};
     use std::str::FromStr;
 
@@ -163,10 +571,35 @@ mod tests {
             "at://did:plc:test/app.bsky.feed.post/post".to_string(),
             6,
         );
-        let side_tracker = SideTracker::new(Some(post), root, entrance);
-        let reply = side_tracker.build_reply();
+        let drift = vec![DriftPoint {
+            idx: 6,
+            topic_similarity: 0.1,
+            parent_similarity: 0.2,
+            relatedness: 0.2,
+        }];
+        let timeline = TimelineEntry::from_drift([&root, &post], &drift);
+        let side_tracker = SideTracker::new(Some(post), root, entrance).with_timeline(timeline);
+        let reply = side_tracker.build_reply(&ReplyTemplate::default());
         assert_eq!(reply.text, "最有可能的歪楼犯：@handle3\n罪证：text post but very v...\nhttps://bsky.app/profile/did:plc:test/post/post");
-        let mention = reply.facets.as_ref().unwrap().get(0).unwrap();
+        assert_eq!(
+            side_tracker.verdict_json(),
+            serde_json::json!({
+                "sidetracker": 6,
+                "derailments": [{"idx": 6, "topic": null}],
+                "rescuer": null,
+                "root": "at://did:plc:test/app.bsky.feed.post/root",
+                "explanation": null,
+                "posts": [{
+                    "idx": 6,
+                    "handle": "handle3",
+                    "uri": "at://did:plc:test/app.bsky.feed.post/post",
+                    "text": "text post but very very long",
+                    "posted_at": null,
+                    "relatedness": 0.2
+                }]
+            })
+        );
+        let mention = reply.facets.as_ref().unwrap().first().unwrap();
         assert_eq!(mention.index.byte_start, 27);
         assert_eq!(mention.index.byte_end, 35);
         let link = reply.facets.as_ref().unwrap().get(1).unwrap();
@@ -193,7 +626,280 @@ mod tests {
             12,
         );
         let side_tracker = SideTracker::new(None, root, entrance);
-        let reply = side_tracker.build_reply();
+        let reply = side_tracker.build_reply(&ReplyTemplate::default());
         assert_eq!(reply.text, "太好了，没有找到歪楼犯");
     }
+
+    #[test]
+    fn test_side_tracker_with_template() {
+        let root = Post::new(
+            Cid::from_str("bafyreihvgtbjqmyo2ocpfic3rgjtvepbopaaaaawcccccsxxxxxw3nnjly").unwrap(),
+            Did::from_str("did:plc:fkjudld5cgxxxxxxxxxxxxxx").unwrap(),
+            "handle1".to_string(),
+            "text_root".to_string(),
+            "at://did:plc:test/app.bsky.feed.post/root".to_string(),
+            1,
+        );
+        let post = Post::new(
+            Cid::from_str("bafyreihvgtbjqmyo2ocpfic3rgjtvepbopbbbbbwaaaaaszzzzzw3nnjly").unwrap(),
+            Did::from_str("did:plc:fkjudld5cgzzzzzzzzzzzzzz").unwrap(),
+            "handle3".to_string(),
+            "short".to_string(),
+            "at://did:plc:test/app.bsky.feed.post/post".to_string(),
+            6,
+        );
+        let template = english_template();
+        let side_tracker = SideTracker::new(Some(post), root.clone(), root.clone());
+        let reply = side_tracker.build_reply(&template);
+        assert_eq!(
+            reply.text,
+            "Most likely sidetracker: @handle3\nEvidence: short\nhttps://bsky.app/profile/did:plc:test/post/post"
+        );
+        let mention = reply.facets.as_ref().unwrap().first().unwrap();
+        assert_eq!(mention.index.byte_start, 25);
+        assert_eq!(mention.index.byte_end, 33);
+        assert_eq!(reply.langs.unwrap().len(), 1);
+
+        let side_tracker = SideTracker::new(None, root.clone(), root.clone());
+        let reply = side_tracker.build_reply(&template);
+        assert_eq!(reply.text, "Great, nobody sidetracked");
+
+        let posted_at = "2024-12-27T03:53:36Z".parse::<DateTime<Utc>>().unwrap();
+        let root = root.with_times(Some(posted_at), None);
+        let post = Post {
+            created_at: Some(posted_at + chrono::Duration::minutes(3)),
+            ..side_tracker_post()
+        };
+        let side_tracker = SideTracker::new(Some(post), root.clone(), root);
+        let reply = side_tracker.build_reply(&template);
+        assert_eq!(
+            reply.text,
+            "Most likely sidetracker: @handle3\nEvidence: short\nDerailed 3m after the root post\nhttps://bsky.app/profile/did:plc:test/post/post"
+        );
+    }
+
+    fn english_template() -> ReplyTemplate {
+        ReplyTemplate {
+            culprit: "Most likely sidetracker: ".to_string(),
+            evidence: "Evidence: ".to_string(),
+            not_found: "Great, nobody sidetracked".to_string(),
+            langs: vec!["en-US".to_string()],
+            topic: "Topic: ".to_string(),
+            reason: "Derailed by: ".to_string(),
+            delay: "Derailed {} after the root post".to_string(),
+            ..ReplyTemplate::default()
+        }
+    }
+
+    fn side_tracker_post() -> Post {
+        Post::new(
+            Cid::from_str("bafyreihvgtbjqmyo2ocpfic3rgjtvepbopbbbbbwaaaaaszzzzzw3nnjly").unwrap(),
+            Did::from_str("did:plc:fkjudld5cgzzzzzzzzzzzzzz").unwrap(),
+            "handle3".to_string(),
+            "short".to_string(),
+            "at://did:plc:test/app.bsky.feed.post/post".to_string(),
+            6,
+        )
+    }
+
+    #[test]
+    fn test_side_tracker_with_explanation() {
+        let root = Post::new(
+            Cid::from_str("bafyreihvgtbjqmyo2ocpfic3rgjtvepbopaaaaawcccccsxxxxxw3nnjly").unwrap(),
+            Did::from_str("did:plc:fkjudld5cgxxxxxxxxxxxxxx").unwrap(),
+            "handle1".to_string(),
+            "how do you cook rice".to_string(),
+            "at://did:plc:test/app.bsky.feed.post/root".to_string(),
+            1,
+        );
+        let post = Post::new(
+            Cid::from_str("bafyreihvgtbjqmyo2ocpfic3rgjtvepbopbbbbbwaaaaaszzzzzw3nnjly").unwrap(),
+            Did::from_str("did:plc:fkjudld5cgzzzzzzzzzzzzzz").unwrap(),
+            "handle3".to_string(),
+            "did anyone watch the match".to_string(),
+            "at://did:plc:test/app.bsky.feed.post/post".to_string(),
+            6,
+        );
+        let template = english_template();
+        let explanation = Explanation {
+            topic: "Cooking rice.".to_string(),
+            reason: "Football has nothing to do with rice.".to_string(),
+        };
+        let side_tracker = SideTracker::new(Some(post.clone()), root.clone(), root.clone())
+            .with_explanation(Some(explanation.clone()));
+        let reply = side_tracker.build_reply(&template);
+        assert_eq!(
+            reply.text,
+            "Most likely sidetracker: @handle3\nEvidence: did anyone watch the...\nTopic: Cooking rice.\nDerailed by: Football has nothing to do with rice.\nhttps://bsky.app/profile/did:plc:test/post/post"
+        );
+        // the link facet follows the explanation
+        let link = reply.facets.as_ref().unwrap().get(1).unwrap();
+        assert_eq!(&reply.text[link.index.byte_start..], post.get_share_uri());
+
+        // long sentences are shortened to stay within the limit
+        let long = Explanation {
+            topic: "rice ".repeat(60),
+            reason: "football ".repeat(60),
+        };
+        let side_tracker = SideTracker::new(Some(post), root.clone(), root.clone())
+            .with_explanation(Some(long.clone()));
+        let reply = side_tracker.build_reply(&template);
+        assert!(reply.text.chars().count() <= MAX_POST_LENGTH);
+        assert!(reply.text.contains("Topic: rice rice"));
+        assert!(reply.text.contains("Derailed by: football"));
+
+        // no room at all
+        assert_eq!(explanation_lines(&template, &long, 30), "");
+
+        let side_tracker =
+            SideTracker::new(None, root.clone(), root).with_explanation(Some(Explanation {
+                reason: String::new(),
+                ..explanation
+            }));
+        let reply = side_tracker.build_reply(&template);
+        assert_eq!(
+            reply.text,
+            "Great, nobody sidetracked\nTopic: Cooking rice."
+        );
+    }
+
+    #[test]
+    fn test_thread_summary() {
+        let root = Post::new(
+            Cid::from_str("bafyreihvgtbjqmyo2ocpfic3rgjtvepbopaaaaawcccccsxxxxxw3nnjly").unwrap(),
+            Did::from_str("did:plc:fkjudld5cgxxxxxxxxxxxxxx").unwrap(),
+            "handle1".to_string(),
+            "how do you cook rice".to_string(),
+            "at://did:plc:test/app.bsky.feed.post/root".to_string(),
+            1,
+        );
+        let entrance = Post::new(
+            Cid::from_str("bafyreihvgtbjqmyo2ocpfic3rgjtvepbopbbbbbwaaaaasyyyyyw3nnjly").unwrap(),
+            Did::from_str("did:plc:fkjudld5cgyyyyyyyyyyyyyy").unwrap(),
+            "handle2".to_string(),
+            "what was this about?".to_string(),
+            "at://did:plc:test/app.bsky.feed.post/entrance".to_string(),
+            12,
+        );
+        let summary = ThreadSummary::new(
+            Summary {
+                topic: "如何煮饭".to_string(),
+                direction: "昨晚的球赛".to_string(),
+                ..Default::default()
+            },
+            root.clone(),
+            entrance.clone(),
+        );
+        let reply = summary.build_reply(&ReplyTemplate::default());
+        assert_eq!(reply.text, "原帖在聊：如何煮饭\n现在聊到：昨晚的球赛");
+        let reply_ref = reply.reply.unwrap();
+        assert_eq!(reply_ref.parent.uri, entrance.uri);
+        assert_eq!(reply_ref.root.uri, root.uri);
+        let Some(Union::Refs(RecordEmbedRefs::AppBskyEmbedRecordMain(quote))) = reply.embed else {
+            panic!("the root is not quoted");
+        };
+        assert_eq!(quote.record.uri, root.uri);
+
+        let long = ThreadSummary::new(
+            Summary {
+                topic: "rice ".repeat(100),
+                direction: "football ".repeat(100),
+                ..Default::default()
+            },
+            root,
+            entrance,
+        );
+        let reply = long.build_reply(&ReplyTemplate::default());
+        assert!(reply.text.chars().count() <= MAX_POST_LENGTH);
+        assert!(reply.text.contains("现在聊到：football"));
+    }
+
+    #[test]
+    fn test_multiple_culprits() {
+        let post = |idx: u32, handle: &str| test_post(idx, handle, "text");
+        let root = post(1, "op");
+        let culprits = vec![
+            Derailment {
+                post: post(4, "alice"),
+                topic: Some("昨晚的球赛".to_string()),
+            },
+            Derailment {
+                post: post(9, "bob"),
+                topic: None,
+            },
+        ];
+        let side_tracker = SideTracker::from_derailments(culprits, root.clone(), root.clone());
+        assert_eq!(side_tracker.sidetracker().unwrap().idx, 4);
+        let reply = side_tracker.build_reply(&ReplyTemplate::default());
+        assert_eq!(reply.text, "歪楼犯们：\n1. @alice → 昨晚的球赛\n2. @bob");
+        let facets = reply.facets.unwrap();
+        assert_eq!(facets.len(), 2);
+        for (facet, handle) in facets.iter().zip(["@alice", "@bob"]) {
+            assert_eq!(
+                &reply.text[facet.index.byte_start..facet.index.byte_end],
+                handle
+            );
+        }
+        let Some(Union::Refs(RecordEmbedRefs::AppBskyEmbedRecordMain(quote))) = reply.embed else {
+            panic!("the first culprit is not quoted");
+        };
+        assert_eq!(quote.record.uri, "at://did:plc:test/app.bsky.feed.post/4");
+
+        // only as many as fit into a post are named
+        let culprits = (0..30)
+            .map(|i| Derailment {
+                post: post(i + 2, &format!("user{}", i)),
+                topic: Some("a topic long enough to take a lot of room".to_string()),
+            })
+            .collect();
+        let side_tracker = SideTracker::from_derailments(culprits, root.clone(), root);
+        let reply = side_tracker.build_reply(&ReplyTemplate::default());
+        assert!(reply.text.chars().count() <= MAX_POST_LENGTH);
+        let named = reply.facets.unwrap().len();
+        assert!(named > 1 && named < 30);
+    }
+
+    #[test]
+    fn test_rescuer() {
+        let root = test_post(1, "op", "how do you cook rice");
+        let side_tracker =
+            SideTracker::new(Some(test_post(4, "alice", "football!")), root.clone(), root)
+                .with_rescuer(Some(test_post(7, "bob", "back to rice")));
+        let reply = side_tracker.build_reply(&ReplyTemplate::default());
+        assert_eq!(
+            reply.text,
+            "最有可能的歪楼犯：@alice\n罪证：football!\n把楼掰回来的好人：@bob\nhttps://bsky.app/profile/did:plc:test/post/4"
+        );
+        let facets = reply.facets.unwrap();
+        let mention = &facets[1];
+        assert_eq!(
+            &reply.text[mention.index.byte_start..mention.index.byte_end],
+            "@bob"
+        );
+        // the link still points at the sidetracker, while the rescuer is quoted
+        assert_eq!(
+            &reply.text[facets[2].index.byte_start..],
+            "https://bsky.app/profile/did:plc:test/post/4"
+        );
+        let Some(Union::Refs(RecordEmbedRefs::AppBskyEmbedRecordMain(quote))) = reply.embed else {
+            panic!("the rescuer is not quoted");
+        };
+        assert_eq!(quote.record.uri, "at://did:plc:test/app.bsky.feed.post/7");
+        assert_eq!(side_tracker.verdict_json()["rescuer"], 7);
+    }
+
+    #[test]
+    fn test_notice() {
+        let post = |idx: u32| test_post(idx, "handle", "text");
+        let template = ReplyTemplate::default();
+        let reply = template.notice(&template.exhausted, (&post(5)).into(), (&post(1)).into());
+        assert_eq!(reply.text, "算力预算用完了，晚点再来找我吧");
+        let reply_ref = reply.reply.unwrap();
+        assert_eq!(
+            reply_ref.parent.uri,
+            "at://did:plc:test/app.bsky.feed.post/5"
+        );
+        assert_eq!(reply_ref.root.uri, "at://did:plc:test/app.bsky.feed.post/1");
+        assert!(reply.embed.is_none());
+    }
 }
@@ -0,0 +1,217 @@
+use crate::config::DetectorBackend;
+use crate::embedding::EmbeddingDetector;
+use crate::examples::Example;
+use crate::local::{self, OllamaClient};
+use crate::openai::{ChatClient, ChatModel, OpenAiDetector};
+use crate::persona::DetectorSettings;
+use crate::post::Post;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+
+/// How close a post stays to the thread.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DriftPoint {
+    pub idx: u32,
+    /// cosine similarity to the topic of the root
+    pub topic_similarity: f64,
+    /// cosine similarity to the post it replies to
+    pub parent_similarity: f64,
+    /// from 0 for off topic to 1 for on topic, either staying on the topic or following the post
+    /// it replies to
+    pub relatedness: f64,
+}
+
+/// What the thread was about and why the sidetracker derails it, in explain mode.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Explanation {
+    /// one sentence about the topic of the root
+    pub topic: String,
+    /// one sentence about why the sidetracker went off topic, empty if nobody did
+    pub reason: String,
+}
+
+/// What a thread was about and where it went.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Summary {
+    /// one sentence about the topic of the root
+    pub topic: String,
+    /// one sentence about what the latest posts talk about
+    pub direction: String,
+    #[serde(skip)]
+    pub usage: Usage,
+}
+
+/// The tokens a request to a chat model takes, zero if the detector uses none or doesn't tell.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+impl Usage {
+    /// The tokens taken by the request failing with the error, which are paid for when the model
+    /// answered before it failed.
+    pub fn of_failure(err: &(dyn Error + 'static)) -> Self {
+        err.downcast_ref::<PaidFailure>()
+            .map(|failure| failure.usage)
+            .unwrap_or_default()
+    }
+}
+
+/// A failure after the model answered, e.g. with an answer that doesn't parse.
+#[derive(Debug)]
+pub struct PaidFailure {
+    pub usage: Usage,
+    pub error: Box<dyn Error>,
+}
+
+impl fmt::Display for PaidFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl Error for PaidFailure {}
+
+/// A post sending the thread off to another topic.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Derailment {
+    pub post: Post,
+    /// a few words about the topic the thread drifted to, if the detector tells
+    pub topic: Option<String>,
+}
+
+impl From<Post> for Derailment {
+    fn from(post: Post) -> Self {
+        Self { post, topic: None }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Verdict {
+    /// the posts derailing the thread in order, at most one unless more are asked for
+    pub derailments: Vec<Derailment>,
+    /// the drift curve of the posts, empty if the detector does not measure it
+    pub drift: Vec<DriftPoint>,
+    /// only given by the detectors able to explain, in explain mode
+    pub explanation: Option<Explanation>,
+    /// the post bringing the thread back to the topic after the sidetracker, if asked for
+    pub rescuer: Option<Post>,
+    /// the tokens the detector took to reach the verdict
+    pub usage: Usage,
+}
+
+impl Verdict {
+    /// The first post derailing the thread.
+    pub fn sidetracker(&self) -> Option<&Post> {
+        self.derailments.first().map(|d| &d.post)
+    }
+
+    /// The verdict without the posts of the original poster, who may take the thread anywhere.
+    pub fn sparing_op(mut self) -> Self {
+        self.derailments.retain(|d| !d.post.by_op);
+        let first = self.sidetracker().map(|p| p.idx);
+        // a rescuer needs a sidetracker before it
+        self.rescuer = self
+            .rescuer
+            .filter(|rescuer| first.is_some_and(|idx| idx < rescuer.idx));
+        if first.is_none() {
+            if let Some(ref mut explanation) = self.explanation {
+                explanation.reason.clear();
+            }
+        }
+        self
+    }
+}
+
+/// Finds the sidetracker of a thread.
+#[async_trait]
+pub trait Detector: Send + Sync {
+    /// the backend and model, which identifies the verdicts in the cache
+    fn name(&self) -> String;
+
+    /// Find the sidetracker of the thread. The first `checked` posts were judged on topic before,
+    /// so only the posts after them can be the answer.
+    async fn locate(
+        &self,
+        thread: &VecDeque<Post>,
+        checked: usize,
+    ) -> Result<Verdict, Box<dyn Error>>;
+
+    /// Summarize the topic of the thread and where the conversation went.
+    async fn summarize(&self, _thread: &VecDeque<Post>) -> Result<Summary, Box<dyn Error>> {
+        Err(format!("the {} detector cannot summarize threads", self.name()).into())
+    }
+
+    /// Check that the backend is reachable.
+    async fn ping(&self) -> Result<(), Box<dyn Error>>;
+}
+
+/// The detector of the settings, with the client of its backend built once here.
+pub fn new_detector(
+    settings: &DetectorSettings,
+    prompt: String,
+    examples: Vec<Example>,
+) -> Result<Box<dyn Detector>, Box<dyn Error>> {
+    let chat = |client: Box<dyn ChatModel>| {
+        OpenAiDetector::new(client, settings.clone(), prompt).with_examples(examples)
+    };
+    Ok(match settings.backend {
+        DetectorBackend::OpenAi => Box::new(chat(Box::new(ChatClient::new(&settings.openai)?))),
+        DetectorBackend::Ollama => Box::new(
+            chat(Box::new(OllamaClient::new(&settings.local)?))
+                .with_name(format!("ollama:{}", settings.model)),
+        ),
+        DetectorBackend::LlamaCpp => Box::new(
+            chat(Box::new(local::llama_cpp_client(&settings.local)?))
+                .with_name(format!("llamacpp:{}", settings.model)),
+        ),
+        DetectorBackend::Embedding => Box::new(
+            EmbeddingDetector::new(&settings.embedding)?
+                .with_max_derailments(settings.max_derailments)
+                .with_rescuer(settings.find_rescuer),
+        ),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::post::test_post;
+
+    fn post(idx: u32, by_op: bool) -> Post {
+        Post {
+            by_op,
+            ..test_post(idx, "user", "text")
+        }
+    }
+
+    #[test]
+    fn test_sparing_op() {
+        let verdict = Verdict {
+            derailments: vec![post(3, true).into(), post(6, false).into()],
+            rescuer: Some(post(8, false)),
+            ..Default::default()
+        }
+        .sparing_op();
+        assert_eq!(verdict.sidetracker().map(|p| p.idx), Some(6));
+        assert_eq!(verdict.rescuer.map(|p| p.idx), Some(8));
+
+        let verdict = Verdict {
+            derailments: vec![post(3, true).into()],
+            explanation: Some(Explanation {
+                topic: "rice".to_string(),
+                reason: "the OP asked about rice cookers".to_string(),
+            }),
+            rescuer: Some(post(5, false)),
+            ..Default::default()
+        }
+        .sparing_op();
+        assert!(verdict.derailments.is_empty());
+        assert!(verdict.rescuer.is_none());
+        assert_eq!(verdict.explanation.unwrap().reason, "");
+    }
+}
@@ -0,0 +1,476 @@
+use crate::config::EmbeddingConfig;
+use crate::detector::{Derailment, Detector, DriftPoint, Verdict};
+use crate::post::{self, Post};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::time::Duration;
+use tracing::debug;
+
+/// dimensions of the built-in hashing embedder
+const HASHING_DIMENSIONS: usize = 512;
+
+/// Turns texts into vectors.
+enum Embedder {
+    /// feature hashing of the words and CJK character bigrams, which needs nothing but the text
+    Hashing,
+    /// an OpenAI compatible `/embeddings` API, e.g. Ollama or llama.cpp running locally
+    Endpoint {
+        client: reqwest::Client,
+        url: String,
+        model: String,
+    },
+}
+
+#[derive(Serialize)]
+struct EmbeddingRequest<'a> {
+    model: &'a str,
+    input: &'a [&'a str],
+}
+
+#[derive(Deserialize)]
+struct EmbeddingResponse {
+    data: Vec<EmbeddingData>,
+}
+
+#[derive(Deserialize)]
+struct EmbeddingData {
+    index: usize,
+    embedding: Vec<f64>,
+}
+
+impl Embedder {
+    async fn embed(&self, texts: &[&str]) -> Result<Vec<Vec<f64>>, Box<dyn Error>> {
+        match self {
+            Embedder::Hashing => Ok(texts.iter().map(|text| hashing_embedding(text)).collect()),
+            Embedder::Endpoint { client, url, model } => {
+                let response = client
+                    .post(format!("{}/embeddings", url))
+                    .json(&EmbeddingRequest {
+                        model,
+                        input: texts,
+                    })
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json::<EmbeddingResponse>()
+                    .await?;
+                if response.data.len() != texts.len() {
+                    return Err(format!(
+                        "expecting {} embeddings, got {}",
+                        texts.len(),
+                        response.data.len()
+                    )
+                    .into());
+                }
+                let mut data = response.data;
+                data.sort_by_key(|d| d.index);
+                Ok(data.into_iter().map(|d| d.embedding).collect())
+            }
+        }
+    }
+}
+
+/// FNV-1a, which is stable across builds unlike the hasher of std.
+fn fnv1a(text: &str) -> u64 {
+    text.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// The features of a text: lowercased words, and CJK characters with their bigrams since there
+/// are no spaces between the words.
+fn features(text: &str) -> Vec<String> {
+    let mut features = Vec::new();
+    let mut word = String::new();
+    let mut previous: Option<char> = None;
+    for c in text.chars().flat_map(char::to_lowercase) {
+        if c.is_ascii_alphanumeric() {
+            word.push(c);
+            previous = None;
+            continue;
+        }
+        if !word.is_empty() {
+            features.push(std::mem::take(&mut word));
+        }
+        if c.is_alphanumeric() {
+            features.push(c.to_string());
+            if let Some(p) = previous {
+                features.push(format!("{}{}", p, c));
+            }
+            previous = Some(c);
+        } else {
+            previous = None;
+        }
+    }
+    if !word.is_empty() {
+        features.push(word);
+    }
+    features
+}
+
+fn hashing_embedding(text: &str) -> Vec<f64> {
+    let mut vector = vec![0.0; HASHING_DIMENSIONS];
+    for feature in features(text) {
+        let hash = fnv1a(&feature);
+        let sign = if hash >> 63 == 0 { 1.0 } else { -1.0 };
+        vector[(hash % HASHING_DIMENSIONS as u64) as usize] += sign;
+    }
+    vector
+}
+
+fn cosine(a: &[f64], b: &[f64]) -> f64 {
+    let dot: f64 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm = |v: &[f64]| v.iter().map(|x| x * x).sum::<f64>().sqrt();
+    let norms = norm(a) * norm(b);
+    if norms == 0.0 {
+        0.0
+    } else {
+        dot / norms
+    }
+}
+
+/// The similarity of each post to the topic, i.e. the mean of the root and the post it quotes if
+/// any, and to its parent.
+fn drift_curve(thread: &VecDeque<Post>, embeddings: &[Vec<f64>]) -> Vec<DriftPoint> {
+    let head = &embeddings[..post::topic_len(thread).min(embeddings.len())];
+    let dimensions = embeddings.first().map(Vec::len).unwrap_or_default();
+    let topic: Vec<f64> = (0..dimensions)
+        .map(|d| head.iter().map(|e| e[d]).sum::<f64>() / head.len() as f64)
+        .collect();
+    thread
+        .iter()
+        .enumerate()
+        .map(|(i, post)| {
+            let topic_similarity = cosine(&embeddings[i], &topic);
+            let parent_similarity = if i == 0 {
+                1.0
+            } else {
+                cosine(&embeddings[i], &embeddings[i - 1])
+            };
+            DriftPoint {
+                idx: post.idx,
+                topic_similarity,
+                parent_similarity,
+                relatedness: topic_similarity.max(parent_similarity).clamp(0.0, 1.0),
+            }
+        })
+        .collect()
+}
+
+/// The drift curve from the built-in embedder, for the detectors not measuring it.
+pub fn offline_drift(thread: &VecDeque<Post>) -> Vec<DriftPoint> {
+    let embeddings: Vec<Vec<f64>> = thread.iter().map(|p| hashing_embedding(&p.text)).collect();
+    drift_curve(thread, &embeddings)
+}
+
+/// The first post from `start` on whose relatedness drops sharply from the posts before it, while
+/// it and the posts after it stay away from the topic.
+fn find_drift(curve: &[DriftPoint], start: usize, config: &EmbeddingConfig) -> Option<usize> {
+    let window = config.window.max(1);
+    (start.max(1)..curve.len()).find(|&i| {
+        // the first post is related to itself, so it only makes the baseline of the first reply
+        let before = if i > 1 { &curve[1..i] } else { &curve[..i] };
+        let baseline = before.iter().map(|p| p.relatedness).sum::<f64>() / before.len() as f64;
+        let after = &curve[i..(i + window).min(curve.len())];
+        let after_topic =
+            after.iter().map(|p| p.topic_similarity).sum::<f64>() / after.len() as f64;
+        baseline - curve[i].relatedness >= config.drop
+            && curve[i].topic_similarity < config.low
+            && after_topic < config.low
+    })
+}
+
+/// The first post after the drift point at `drift` coming back to the topic.
+fn find_return(curve: &[DriftPoint], drift: usize, config: &EmbeddingConfig) -> Option<usize> {
+    (drift + 1..curve.len()).find(|&j| curve[j].topic_similarity >= config.low)
+}
+
+/// Find up to `max` drift points after the first `checked` posts and the `topic` ones. The thread
+/// has to come back to the topic before it drifts away again.
+fn find_drifts(
+    curve: &[DriftPoint],
+    topic: usize,
+    checked: usize,
+    config: &EmbeddingConfig,
+    max: usize,
+) -> Vec<usize> {
+    let mut drifts = Vec::new();
+    let mut start = topic.max(checked);
+    while drifts.len() < max {
+        let Some(i) = find_drift(curve, start, config) else {
+            break;
+        };
+        drifts.push(i);
+        match find_return(curve, i, config) {
+            Some(back) => start = back + 1,
+            None => break,
+        }
+    }
+    drifts
+}
+
+/// Finds the sidetracker from how far the posts drift away from the topic, with no LLM at all.
+pub struct EmbeddingDetector {
+    embedder: Embedder,
+    config: EmbeddingConfig,
+    max_derailments: usize,
+    find_rescuer: bool,
+}
+
+impl EmbeddingDetector {
+    pub fn new(config: &EmbeddingConfig) -> Result<Self, Box<dyn Error>> {
+        let embedder = match config.endpoint {
+            Some(ref endpoint) => Embedder::Endpoint {
+                client: reqwest::Client::builder()
+                    .timeout(Duration::from_secs(config.timeout))
+                    .build()?,
+                url: endpoint.trim_end_matches('/').to_string(),
+                model: config.model.clone(),
+            },
+            None => Embedder::Hashing,
+        };
+        Ok(Self {
+            embedder,
+            config: config.clone(),
+            max_derailments: 1,
+            find_rescuer: false,
+        })
+    }
+
+    pub fn with_max_derailments(self, max_derailments: usize) -> Self {
+        Self {
+            max_derailments: max_derailments.max(1),
+            ..self
+        }
+    }
+
+    pub fn with_rescuer(self, find_rescuer: bool) -> Self {
+        Self {
+            find_rescuer,
+            ..self
+        }
+    }
+}
+
+#[async_trait]
+impl Detector for EmbeddingDetector {
+    fn name(&self) -> String {
+        match self.embedder {
+            Embedder::Hashing => "embedding:hashing".to_string(),
+            Embedder::Endpoint { ref model, .. } => format!("embedding:{}", model),
+        }
+    }
+
+    async fn locate(
+        &self,
+        thread: &VecDeque<Post>,
+        checked: usize,
+    ) -> Result<Verdict, Box<dyn Error>> {
+        if thread.is_empty() {
+            return Ok(Verdict::default());
+        }
+        let texts: Vec<&str> = thread.iter().map(|p| p.text.as_str()).collect();
+        let embeddings = self.embedder.embed(&texts).await?;
+        let drift = drift_curve(thread, &embeddings);
+        debug!("drift curve: {:?}", drift);
+        let topic = post::topic_len(thread);
+        let drifts = find_drifts(&drift, topic, checked, &self.config, self.max_derailments);
+        let rescuer = drifts
+            .first()
+            .filter(|_| self.find_rescuer)
+            .and_then(|i| find_return(&drift, *i, &self.config))
+            .map(|j| thread[j].clone());
+        let derailments = drifts
+            .into_iter()
+            .map(|i| Derailment::from(thread[i].clone()))
+            .collect();
+        Ok(Verdict {
+            derailments,
+            rescuer,
+            drift,
+            ..Default::default()
+        })
+    }
+
+    async fn ping(&self) -> Result<(), Box<dyn Error>> {
+        self.embedder
+            .embed(&["ping"])
+            .await
+            .map_err(|err| format!("embedding endpoint is unavailable: {}", err))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::post::test_thread;
+    use mockito::Matcher::PartialJsonString;
+    use mockito::Server;
+
+    #[test]
+    fn test_features() {
+        assert_eq!(features("Cook rice!"), vec!["cook", "rice"]);
+        assert_eq!(features("猛吃！"), vec!["猛", "吃", "猛吃"]);
+        assert_eq!(features("ok 好的"), vec!["ok", "好", "的", "好的"]);
+    }
+
+    #[test]
+    fn test_hashing_embedding() {
+        let a = hashing_embedding("how to cook rice");
+        let b = hashing_embedding("cook the rice slowly");
+        let c = hashing_embedding("football match yesterday");
+        assert_eq!(a.len(), HASHING_DIMENSIONS);
+        assert!((cosine(&a, &a) - 1.0).abs() < 1e-9);
+        assert!(cosine(&a, &b) > cosine(&a, &c));
+        assert_eq!(cosine(&a, &vec![0.0; HASHING_DIMENSIONS]), 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_locate_offline() {
+        let thread = test_thread(&[
+            "what is the best way to cook rice",
+            "rinse the rice and cook it with less water",
+            "cook rice in a pot with the lid on",
+            "did anyone watch the football match yesterday",
+            "yes the football match was great",
+            "the football team played so well",
+        ]);
+        let detector = EmbeddingDetector::new(&EmbeddingConfig::default()).unwrap();
+        assert_eq!(detector.name(), "embedding:hashing");
+        let verdict = detector.locate(&thread, 0).await.unwrap();
+        assert_eq!(verdict.sidetracker().unwrap().idx, 4);
+        assert_eq!(verdict.drift.len(), 6);
+        assert_eq!(verdict.drift[3].idx, 4);
+        assert!(verdict.drift[3].topic_similarity < verdict.drift[2].topic_similarity);
+
+        assert!(verdict
+            .drift
+            .iter()
+            .all(|p| (0.0..=1.0).contains(&p.relatedness)));
+        assert_eq!(offline_drift(&thread), verdict.drift);
+
+        // the sidetracker was checked before
+        let verdict = detector.locate(&thread, 5).await.unwrap();
+        assert!(verdict.sidetracker().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_locate_multiple_offline() {
+        let thread = test_thread(&[
+            "what is the best way to cook rice",
+            "rinse the rice and cook it with less water",
+            "cook rice in a pot with the lid on",
+            "did anyone watch the football match yesterday",
+            "yes the football match was great",
+            "the football team played so well",
+            "back to rice, what is the best way to cook rice with less water",
+            "cook the rice in a pot, rinse the rice first",
+            "my cat just knocked over a plant",
+            "cats always knock plants over",
+            "my cat does that to every plant too",
+        ]);
+        let detector = EmbeddingDetector::new(&EmbeddingConfig::default()).unwrap();
+        let verdict = detector.locate(&thread, 0).await.unwrap();
+        assert_eq!(verdict.derailments.len(), 1);
+
+        let detector = detector.with_max_derailments(3);
+        let verdict = detector.locate(&thread, 0).await.unwrap();
+        let idx: Vec<u32> = verdict.derailments.iter().map(|d| d.post.idx).collect();
+        assert_eq!(idx, vec![4, 9]);
+        assert!(verdict.derailments.iter().all(|d| d.topic.is_none()));
+        assert!(verdict.rescuer.is_none());
+
+        let detector = detector.with_rescuer(true);
+        let verdict = detector.locate(&thread, 0).await.unwrap();
+        assert_eq!(verdict.rescuer.unwrap().idx, 7);
+    }
+
+    #[tokio::test]
+    async fn test_locate_first_reply() {
+        let mut thread = test_thread(&[
+            "what is the best way to cook rice",
+            "did anyone watch the football match yesterday",
+            "yes the football match was great",
+            "the football team played so well",
+        ]);
+        let detector = EmbeddingDetector::new(&EmbeddingConfig::default()).unwrap();
+        // the root quotes nothing, so the first reply can derail it
+        let verdict = detector.locate(&thread, 0).await.unwrap();
+        assert_eq!(verdict.sidetracker().unwrap().idx, 2);
+
+        // when the root is the football post quoting the first one, the thread stays on topic
+        thread[0].quoted = true;
+        let verdict = detector.locate(&thread, 0).await.unwrap();
+        assert!(verdict.sidetracker().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_locate_on_topic() {
+        let thread = test_thread(&[
+            "what is the best way to cook rice",
+            "rinse the rice first",
+            "cook the rice with less water",
+            "a rice cooker makes rice easy",
+        ]);
+        let detector = EmbeddingDetector::new(&EmbeddingConfig::default()).unwrap();
+        let verdict = detector.locate(&thread, 0).await.unwrap();
+        assert!(verdict.sidetracker().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_locate_with_endpoint() {
+        let mut server = Server::new_async().await;
+        let mock = server
+            .mock("POST", "/v1/embeddings")
+            .match_body(PartialJsonString(
+                r#"{"model": "nomic-embed-text", "input": ["rice", "rice too", "football"]}"#
+                    .to_string(),
+            ))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"{"data": [
+                    {"index": 2, "embedding": [0.0, 1.0]},
+                    {"index": 0, "embedding": [1.0, 0.0]},
+                    {"index": 1, "embedding": [1.0, 0.1]}
+                ]}"#,
+            )
+            .create_async()
+            .await;
+        let detector = EmbeddingDetector::new(&EmbeddingConfig {
+            endpoint: Some(format!("{}/v1/", server.url())),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(detector.name(), "embedding:nomic-embed-text");
+        let thread = test_thread(&["rice", "rice too", "football"]);
+        let verdict = detector.locate(&thread, 0).await.unwrap();
+        mock.assert_async().await;
+        assert_eq!(verdict.sidetracker().unwrap().idx, 3);
+        assert!(verdict.drift[2].topic_similarity < 0.1);
+    }
+
+    #[tokio::test]
+    async fn test_ping_endpoint() {
+        let mut server = Server::new_async().await;
+        server
+            .mock("POST", "/embeddings")
+            .with_status(500)
+            .create_async()
+            .await;
+        let detector = EmbeddingDetector::new(&EmbeddingConfig {
+            endpoint: Some(server.url()),
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(detector.ping().await.is_err());
+        assert!(EmbeddingDetector::new(&EmbeddingConfig::default())
+            .unwrap()
+            .ping()
+            .await
+            .is_ok());
+    }
+}
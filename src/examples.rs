@@ -0,0 +1,138 @@
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::path::Path;
+
+/// the built-in example bank
+const DEFAULT_EXAMPLES: &str = include_str!("../data/examples.json");
+
+/// A thread with the right answer, shown to the model before the thread to check.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Example {
+    /// the language of the thread, as told by `detect_lang`
+    pub lang: String,
+    /// the texts of the posts, from the root
+    pub posts: Vec<String>,
+    /// the aliases of the authors of the posts as in the prompt, e.g. `OP`, `A`
+    #[serde(default)]
+    pub authors: Vec<String>,
+    /// the number of the sidetracking post, 0 if nobody sidetracked
+    pub answer: u32,
+}
+
+/// Load the example bank from the file, or the built-in one.
+pub async fn load_examples(path: Option<&Path>) -> Result<Vec<Example>, Box<dyn Error>> {
+    let content = match path {
+        Some(path) => tokio::fs::read_to_string(path)
+            .await
+            .map_err(|err| format!("failed to read example file {}: {}", path.display(), err))?,
+        None => DEFAULT_EXAMPLES.to_string(),
+    };
+    serde_json::from_str(&content).map_err(|err| format!("invalid examples: {}", err).into())
+}
+
+fn is_kana(c: char) -> bool {
+    matches!(c, '\u{3040}'..='\u{30ff}')
+}
+
+fn is_han(c: char) -> bool {
+    matches!(c, '\u{4e00}'..='\u{9fff}' | '\u{3400}'..='\u{4dbf}')
+}
+
+fn is_hangul(c: char) -> bool {
+    matches!(c, '\u{ac00}'..='\u{d7af}')
+}
+
+/// A rough guess of the language of the texts from the scripts they are written in.
+pub fn detect_lang<'a>(texts: impl IntoIterator<Item = &'a str>) -> &'static str {
+    let (mut letters, mut kana, mut han, mut hangul) = (0, 0, 0, 0);
+    for c in texts.into_iter().flat_map(str::chars) {
+        if is_kana(c) {
+            kana += 1;
+        } else if is_han(c) {
+            han += 1;
+        } else if is_hangul(c) {
+            hangul += 1;
+        } else if c.is_alphabetic() {
+            letters += 1;
+        }
+    }
+    // a word of latin letters weighs about as much as a CJK character
+    let latin = letters / 4;
+    if kana > 0 && kana + han > latin {
+        "ja"
+    } else if hangul > han && hangul > latin {
+        "ko"
+    } else if han > latin {
+        "zh"
+    } else {
+        "en"
+    }
+}
+
+/// The `count` examples most relevant to a thread of `posts` posts in `lang`: those in the same
+/// language first, then those of the closest length.
+pub fn select<'a>(
+    examples: &'a [Example],
+    lang: &str,
+    posts: usize,
+    count: usize,
+) -> Vec<&'a Example> {
+    let mut ranked: Vec<&Example> = examples.iter().collect();
+    ranked.sort_by_key(|e| (e.lang != lang, e.posts.len().abs_diff(posts)));
+    ranked.truncate(count);
+    ranked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example(lang: &str, posts: usize) -> Example {
+        Example {
+            lang: lang.to_string(),
+            posts: vec!["post".to_string(); posts],
+            authors: Vec::new(),
+            answer: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_load_examples() {
+        let examples = load_examples(None).await.unwrap();
+        assert!(examples.len() >= 4);
+        for example in &examples {
+            let texts = example.posts.iter().map(|p| p.as_str());
+            assert_eq!(detect_lang(texts), example.lang);
+            assert!(example.answer as usize <= example.posts.len());
+            assert_eq!(example.authors.len(), example.posts.len());
+        }
+        assert!(load_examples(Some(Path::new("data/missing.json")))
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn test_detect_lang() {
+        assert_eq!(detect_lang(["大家平时煮米饭水放多少？"]), "zh");
+        assert_eq!(detect_lang(["ご飯の炊き方を教えて"]), "ja");
+        assert_eq!(detect_lang(["밥은 어떻게 해요"]), "ko");
+        assert_eq!(detect_lang(["how do you cook rice", "用电饭煲"]), "en");
+        assert_eq!(detect_lang(["用电饭煲煮饭最方便了", "rice cooker"]), "zh");
+        assert_eq!(detect_lang([""]), "en");
+    }
+
+    #[test]
+    fn test_select() {
+        let examples = vec![
+            example("en", 5),
+            example("zh", 12),
+            example("zh", 4),
+            example("en", 9),
+        ];
+        let selected = select(&examples, "zh", 5, 2);
+        assert_eq!(selected, vec![&examples[2], &examples[1]]);
+        let selected = select(&examples, "en", 10, 3);
+        assert_eq!(selected, vec![&examples[3], &examples[0], &examples[1]]);
+        assert!(select(&examples, "en", 10, 0).is_empty());
+    }
+}
@@ -0,0 +1,313 @@
+use crate::util;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+use tracing::warn;
+
+/// How a check ended, or a summary, recorded for what it cost.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    /// a sidetracker is found
+    Found,
+    /// nobody sidetracked
+    Clean,
+    /// the detector failed, so there is no verdict and no reply
+    Failed,
+    /// the thread is summarized instead of checked, or the answer of the model is no summary
+    Summarized,
+}
+
+/// A verdict with the prompt and the model producing it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub checked_at: DateTime<Utc>,
+    pub persona: String,
+    /// the post the check was summoned at
+    pub thread: String,
+    /// the experiment variant the summon is assigned to
+    pub variant: String,
+    pub prompt_id: String,
+    pub prompt_version: String,
+    /// the detector with its model, e.g. `openai:gpt-4o-mini`
+    pub model: String,
+    pub outcome: Outcome,
+    /// how many derail points are reported
+    pub derailments: usize,
+    /// whether the verdict came from the cache
+    pub cached: bool,
+    #[serde(default)]
+    pub prompt_tokens: u64,
+    #[serde(default)]
+    pub completion_tokens: u64,
+    /// what the tokens cost in USD, 0 if the price of the model is unknown
+    #[serde(default)]
+    pub cost: f64,
+}
+
+/// The verdicts on disk, one JSON object per line, the oldest first.
+#[derive(Debug, Clone)]
+pub struct History {
+    path: PathBuf,
+}
+
+/// The default history file, `$XDG_STATE_HOME/rust-sidetracker-bot/history.jsonl`.
+pub fn default_history_file() -> PathBuf {
+    util::xdg_app_dir("XDG_STATE_HOME", ".local/state").join("history.jsonl")
+}
+
+impl History {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub async fn append(&self, entry: &HistoryEntry) -> Result<(), Box<dyn Error>> {
+        if let Some(parent) = self.path.parent() {
+            util::create_private_dir(parent).await?;
+        }
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        // a single write of a line in append mode doesn't interleave with concurrent checks
+        let mut file = tokio::fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .open(&self.path)
+            .await?;
+        file.write_all(&line).await?;
+        Ok(())
+    }
+
+    /// All entries, skipping the broken lines. A missing file means no history.
+    pub async fn load(&self) -> Result<Vec<HistoryEntry>, Box<dyn Error>> {
+        let content = match tokio::fs::read_to_string(&self.path).await {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+        let mut entries = Vec::new();
+        for (number, line) in content.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str(line) {
+                Ok(entry) => entries.push(entry),
+                Err(err) => warn!(
+                    "ignoring broken history line {} of {}: {}",
+                    number + 1,
+                    self.path.display(),
+                    err
+                ),
+            }
+        }
+        Ok(entries)
+    }
+}
+
+/// The outcomes of the checks with a variant, and what the checks and the summaries cost.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VariantStats {
+    pub checks: usize,
+    pub found: usize,
+    pub clean: usize,
+    pub failed: usize,
+    pub cached: usize,
+    pub derailments: usize,
+    pub cost: f64,
+}
+
+impl VariantStats {
+    fn add(&mut self, entry: &HistoryEntry) {
+        self.cost += entry.cost;
+        match entry.outcome {
+            Outcome::Found => self.found += 1,
+            Outcome::Clean => self.clean += 1,
+            Outcome::Failed => self.failed += 1,
+            Outcome::Summarized => return,
+        }
+        self.checks += 1;
+        if entry.cached {
+            self.cached += 1;
+        }
+        self.derailments += entry.derailments;
+    }
+
+    /// The share of the answered checks finding a sidetracker.
+    pub fn found_rate(&self) -> f64 {
+        let answered = self.found + self.clean;
+        if answered == 0 {
+            0.0
+        } else {
+            self.found as f64 / answered as f64
+        }
+    }
+}
+
+/// The stats by `(variant, prompt id, model)`, of the entries checked since the time if given.
+pub fn stats(
+    entries: &[HistoryEntry],
+    since: Option<DateTime<Utc>>,
+) -> BTreeMap<(String, String, String), VariantStats> {
+    let mut stats: BTreeMap<_, VariantStats> = BTreeMap::new();
+    for entry in entries {
+        if since.is_some_and(|since| entry.checked_at < since) {
+            continue;
+        }
+        let key = (
+            entry.variant.clone(),
+            entry.prompt_id.clone(),
+            entry.model.clone(),
+        );
+        stats.entry(key).or_default().add(entry);
+    }
+    stats
+}
+
+/// Print the stats as a table, for the `stats` command.
+pub async fn print_stats(history: &History, days: Option<u32>) -> Result<(), Box<dyn Error>> {
+    let entries = history.load().await?;
+    let since = days.map(|days| Utc::now() - chrono::Duration::days(days as i64));
+    let by_variant = stats(&entries, since);
+    if by_variant.is_empty() {
+        println!("no verdict recorded in {}", history.path().display());
+        return Ok(());
+    }
+    println!(
+        "{:<12} {:<12} {:<24} {:>6} {:>6} {:>6} {:>6} {:>6} {:>7} {:>9}",
+        "variant",
+        "prompt",
+        "model",
+        "checks",
+        "found",
+        "clean",
+        "failed",
+        "cached",
+        "found%",
+        "cost$"
+    );
+    for ((variant, prompt_id, model), stats) in by_variant {
+        println!(
+            "{:<12} {:<12} {:<24} {:>6} {:>6} {:>6} {:>6} {:>6} {:>6.1}% {:>9.4}",
+            variant,
+            prompt_id,
+            model,
+            stats.checks,
+            stats.found,
+            stats.clean,
+            stats.failed,
+            stats.cached,
+            stats.found_rate() * 100.0,
+            stats.cost
+        );
+    }
+    Ok(())
+}
+
+/// A check of the thread with a verdict of the outcome, costing $0.5.
+#[cfg(test)]
+pub(crate) fn test_entry(
+    variant: &str,
+    outcome: Outcome,
+    checked_at: DateTime<Utc>,
+) -> HistoryEntry {
+    HistoryEntry {
+        checked_at,
+        persona: "default".to_string(),
+        thread: "at://did:plc:test/app.bsky.feed.post/1".to_string(),
+        variant: variant.to_string(),
+        prompt_id: "builtin".to_string(),
+        prompt_version: "0123456789ab".to_string(),
+        model: "openai:gpt-4o-mini".to_string(),
+        derailments: (outcome == Outcome::Found) as usize,
+        cached: false,
+        prompt_tokens: 100,
+        completion_tokens: 1,
+        cost: 0.5,
+        outcome,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_append_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let history = History::new(dir.path().join("state/history.jsonl"));
+        assert!(history.load().await.unwrap().is_empty());
+
+        let entries = vec![
+            test_entry("control", Outcome::Found, Utc::now()),
+            test_entry("treatment", Outcome::Clean, Utc::now()),
+        ];
+        for entry in &entries {
+            history.append(entry).await.unwrap();
+        }
+        let mut content = tokio::fs::read_to_string(history.path()).await.unwrap();
+        content.push_str("not json\n");
+        tokio::fs::write(history.path(), content).await.unwrap();
+        assert_eq!(history.load().await.unwrap(), entries);
+
+        // recorded before the costs were
+        let old: HistoryEntry = serde_json::from_str(
+            r#"{"checked_at": "2025-01-01T00:00:00Z", "persona": "default", "thread": "at://x",
+                "variant": "default", "prompt_id": "builtin", "prompt_version": "0123456789ab",
+                "model": "gpt-4o-mini", "outcome": "clean", "derailments": 0, "cached": false}"#,
+        )
+        .unwrap();
+        assert_eq!((old.prompt_tokens, old.cost), (0, 0.0));
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut cached = test_entry("control", Outcome::Clean, Utc::now());
+        cached.cached = true;
+        let entries = vec![
+            test_entry("control", Outcome::Found, Utc::now()),
+            cached,
+            test_entry("control", Outcome::Failed, Utc::now()),
+            test_entry("control", Outcome::Summarized, Utc::now()),
+            test_entry("treatment", Outcome::Found, Utc::now()),
+            test_entry(
+                "treatment",
+                Outcome::Found,
+                Utc::now() - chrono::Duration::days(30),
+            ),
+        ];
+        let by_variant = stats(&entries, None);
+        assert_eq!(by_variant.len(), 2);
+        let key = |variant: &str| {
+            (
+                variant.to_string(),
+                "builtin".to_string(),
+                "openai:gpt-4o-mini".to_string(),
+            )
+        };
+        let control = &by_variant[&key("control")];
+        assert_eq!(
+            *control,
+            VariantStats {
+                checks: 3,
+                found: 1,
+                clean: 1,
+                failed: 1,
+                cached: 1,
+                derailments: 1,
+                cost: 2.0,
+            }
+        );
+        assert_eq!(control.found_rate(), 0.5);
+        assert_eq!(by_variant[&key("treatment")].checks, 2);
+
+        let since = Some(Utc::now() - chrono::Duration::days(7));
+        assert_eq!(stats(&entries, since)[&key("treatment")].checks, 1);
+    }
+}
@@ -0,0 +1,149 @@
+use crate::data::SideTracker;
+use ellipse::Ellipse;
+use unicode_segmentation::UnicodeSegmentation;
+
+/// the width of the score bar
+const BAR_WIDTH: usize = 10;
+/// how much of a post is shown
+const SNIPPET_LENGTH: usize = 30;
+
+fn score_bar(score: f64) -> String {
+    let filled = (score.clamp(0.0, 1.0) * BAR_WIDTH as f64).round() as usize;
+    format!("{}{}", "█".repeat(filled), "░".repeat(BAR_WIDTH - filled))
+}
+
+/// Render the posts of the checked thread as a timeline of how related each stays to the topic.
+pub fn render_timeline(result: &SideTracker) -> String {
+    let culprits: Vec<u32> = result.culprits().iter().map(|d| d.post.idx).collect();
+    let rescuer = result.rescuer().map(|p| p.idx);
+    let handle_width = result
+        .timeline()
+        .iter()
+        .map(|e| e.handle.chars().count())
+        .max()
+        .unwrap_or(0);
+    let mut output = String::new();
+    for entry in result.timeline() {
+        let text = entry.text.split_whitespace().collect::<Vec<_>>().join(" ");
+        let snippet = text.as_str().truncate_ellipse(SNIPPET_LENGTH);
+        // the snippet is cut by graphemes, so emoji made of several chars count as one
+        let padding = (SNIPPET_LENGTH + 3).saturating_sub(snippet.graphemes(true).count());
+        output.push_str(&format!(
+            "{:>3}  @{:<handle_width$}  {}{}  {} {:.2}",
+            entry.idx,
+            entry.handle,
+            snippet,
+            " ".repeat(padding),
+            score_bar(entry.relatedness),
+            entry.relatedness,
+        ));
+        match culprits.iter().position(|idx| *idx == entry.idx) {
+            Some(0) => output.push_str("  <- sidetracker"),
+            Some(_) => output.push_str("  <- derailed again"),
+            None if rescuer == Some(entry.idx) => output.push_str("  <- back on track"),
+            None => {}
+        }
+        output.push('\n');
+    }
+    if culprits.is_empty() {
+        output.push_str("no sidetracker found\n");
+    }
+    if let Some(explanation) = result.explanation() {
+        output.push_str(&format!("topic: {}\n", explanation.topic));
+        if !explanation.reason.is_empty() {
+            output.push_str(&format!("reason: {}\n", explanation.reason));
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::TimelineEntry;
+    use crate::detector::{DriftPoint, Explanation};
+    use crate::post::test_post as post;
+
+    #[test]
+    fn test_score_bar() {
+        assert_eq!(score_bar(0.0), "░░░░░░░░░░");
+        assert_eq!(score_bar(0.44), "████░░░░░░");
+        assert_eq!(score_bar(1.5), "██████████");
+    }
+
+    #[test]
+    fn test_render_timeline() {
+        let posts = [
+            post(1, "alice", "how do you cook rice"),
+            post(
+                2,
+                "bob",
+                "rinse it first,\nthen boil it in a pot with twice the water",
+            ),
+            post(3, "carol", "did anyone watch the match"),
+        ];
+        let drift: Vec<DriftPoint> = [1.0, 0.8, 0.1]
+            .iter()
+            .zip(1..)
+            .map(|(relatedness, idx)| DriftPoint {
+                idx,
+                topic_similarity: *relatedness,
+                parent_similarity: *relatedness,
+                relatedness: *relatedness,
+            })
+            .collect();
+        let result = SideTracker::new(Some(posts[2].clone()), posts[0].clone(), posts[2].clone())
+            .with_timeline(TimelineEntry::from_drift(&posts, &drift));
+
+        let lines: Vec<String> = render_timeline(&result)
+            .lines()
+            .map(|l| l.to_string())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("  1  @alice  how do you cook rice"));
+        assert!(lines[0].ends_with("██████████ 1.00"));
+        assert!(lines[1].contains("@bob    rinse it first, then boil it i..."));
+        assert!(lines[2].ends_with("█░░░░░░░░░ 0.10  <- sidetracker"));
+        // the bars line up
+        let bar = |l: &String| l.chars().position(|c| c == '█' || c == '░');
+        assert_eq!(bar(&lines[0]), bar(&lines[1]));
+        assert_eq!(bar(&lines[1]), bar(&lines[2]));
+
+        let result = SideTracker::new(None, posts[0].clone(), posts[2].clone()).with_explanation(
+            Some(Explanation {
+                topic: "cooking rice".to_string(),
+                reason: String::new(),
+            }),
+        );
+        assert_eq!(
+            render_timeline(&result),
+            "no sidetracker found\ntopic: cooking rice\n"
+        );
+    }
+
+    #[test]
+    fn test_render_timeline_with_emoji() {
+        let family = "👨\u{200d}👩\u{200d}👧";
+        let posts = [
+            post(1, "alice", &family.repeat(40)),
+            post(2, "bob", &format!("{} 🍚", family)),
+        ];
+        let drift: Vec<DriftPoint> = [1.0, 0.9]
+            .iter()
+            .zip(1..)
+            .map(|(relatedness, idx)| DriftPoint {
+                idx,
+                topic_similarity: *relatedness,
+                parent_similarity: *relatedness,
+                relatedness: *relatedness,
+            })
+            .collect();
+        let result = SideTracker::new(None, posts[0].clone(), posts[1].clone())
+            .with_timeline(TimelineEntry::from_drift(&posts, &drift));
+        let rendered = render_timeline(&result);
+        let lines: Vec<&str> = rendered.lines().collect();
+        assert!(lines[0].contains(&format!("{}...", family.repeat(SNIPPET_LENGTH))));
+        let bar = |l: &str| l.graphemes(true).position(|g| g == "█" || g == "░");
+        assert_eq!(bar(lines[0]), bar(lines[1]));
+    }
+}
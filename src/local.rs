@@ -0,0 +1,237 @@
+use crate::config::{LocalConfig, OpenAiConfig};
+use crate::openai::{self, Answer, AnswerMessage, ChatClient, ChatMessage, ChatModel};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::time::Duration;
+
+const OLLAMA_URL: &str = "http://localhost:11434";
+const LLAMA_CPP_URL: &str = "http://localhost:8080";
+
+#[derive(Serialize)]
+struct OllamaRequest<'a> {
+    model: &'a str,
+    messages: &'a [ChatMessage],
+    stream: bool,
+    options: OllamaOptions,
+}
+
+#[derive(Serialize)]
+struct OllamaOptions {
+    temperature: f64,
+}
+
+#[derive(Deserialize)]
+struct OllamaResponse {
+    message: AnswerMessage,
+    prompt_eval_count: Option<u64>,
+    eval_count: Option<u64>,
+}
+
+/// A client of the `/api/chat` of Ollama.
+pub struct OllamaClient {
+    client: reqwest::Client,
+    url: String,
+    temperature: f64,
+}
+
+impl OllamaClient {
+    pub fn new(config: &LocalConfig) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(config.timeout))
+                .build()?,
+            url: config
+                .url
+                .as_deref()
+                .unwrap_or(OLLAMA_URL)
+                .trim_end_matches('/')
+                .to_string(),
+            temperature: config.temperature,
+        })
+    }
+}
+
+#[async_trait]
+impl ChatModel for OllamaClient {
+    fn url(&self) -> &str {
+        &self.url
+    }
+
+    async fn chat(&self, model: &str, messages: &[ChatMessage]) -> Result<Answer, Box<dyn Error>> {
+        let response = self
+            .client
+            .post(format!("{}/api/chat", self.url))
+            .json(&OllamaRequest {
+                model,
+                messages,
+                stream: false,
+                options: OllamaOptions {
+                    temperature: self.temperature,
+                },
+            })
+            .send()
+            .await?;
+        let response = openai::success(response).await?;
+        let response: OllamaResponse = response.json().await?;
+        Ok(Answer {
+            content: response.message.content.unwrap_or_default(),
+            prompt_tokens: response.prompt_eval_count,
+            completion_tokens: response.eval_count,
+        })
+    }
+
+    async fn ping(&self, model: &str) -> Result<(), Box<dyn Error>> {
+        let body: serde_json::Value = self
+            .client
+            .get(format!("{}/api/tags", self.url))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|err| format!("{} is unavailable: {}", self.url, err))?
+            .json()
+            .await?;
+        let served = body["models"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|m| m["name"].as_str())
+            .any(|name| name == model || name.strip_suffix(":latest") == Some(model));
+        if !served {
+            return Err(format!("model {} is not pulled into {}", model, self.url).into());
+        }
+        Ok(())
+    }
+}
+
+/// A client of the OpenAI compatible API of a llama.cpp server, which asks for no key and serves
+/// the one model it is started with.
+pub fn llama_cpp_client(config: &LocalConfig) -> Result<ChatClient, Box<dyn Error>> {
+    let url = config.url.as_deref().unwrap_or(LLAMA_CPP_URL);
+    let client = ChatClient::new(&OpenAiConfig {
+        base_url: format!("{}/v1", url.trim_end_matches('/')),
+        api_key_env: String::new(),
+        timeout: config.timeout,
+        ..Default::default()
+    })?;
+    Ok(client
+        .with_temperature(config.temperature)
+        .serving_any_model())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::DetectorBackend;
+    use crate::detector::{self, Detector};
+    use crate::persona::{DetectorSettings, Persona};
+    use crate::post::test_thread;
+    use mockito::Matcher::PartialJsonString;
+    use mockito::Server;
+
+    fn settings(backend: DetectorBackend, url: &str) -> DetectorSettings {
+        let mut settings = Persona::new("test", "test.handle").detector;
+        settings.backend = backend;
+        settings.model = "qwen2.5:7b".to_string();
+        settings.local.url = Some(url.to_string());
+        settings.local.temperature = 0.2;
+        settings
+    }
+
+    fn detector(settings: DetectorSettings) -> Box<dyn Detector> {
+        detector::new_detector(&settings, "prompt".to_string(), Vec::new()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_ollama_locate() {
+        let mut server = Server::new_async().await;
+        let mock = server
+            .mock("POST", "/api/chat")
+            .match_body(PartialJsonString(
+                r#"{"model": "qwen2.5:7b", "stream": false, "options": {"temperature": 0.2}}"#
+                    .to_string(),
+            ))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"{"model": "qwen2.5:7b", "done": true,
+                    "message": {"role": "assistant", "content": "3"},
+                    "prompt_eval_count": 120, "eval_count": 1}"#,
+            )
+            .create_async()
+            .await;
+        let detector = detector(settings(
+            DetectorBackend::Ollama,
+            &format!("{}/", server.url()),
+        ));
+        assert_eq!(detector.name(), "ollama:qwen2.5:7b");
+        let thread = test_thread(&["rice", "rice too", "football"]);
+        let verdict = detector.locate(&thread, 0).await.unwrap();
+        mock.assert_async().await;
+        assert_eq!(verdict.sidetracker().unwrap().idx, 3);
+    }
+
+    #[tokio::test]
+    async fn test_llama_cpp_summarize() {
+        let mut server = Server::new_async().await;
+        let mock = server
+            .mock("POST", "/v1/chat/completions")
+            .match_body(PartialJsonString(
+                r#"{"model": "qwen2.5:7b", "temperature": 0.2, "stream": false}"#.to_string(),
+            ))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            // no id, object nor created, which llama.cpp may leave out
+            .with_body(
+                r#"{"choices": [{"index": 0, "message": {"role": "assistant",
+                    "content": "TOPIC: cooking rice\nNOW: football"}}]}"#,
+            )
+            .create_async()
+            .await;
+        let detector = detector(settings(DetectorBackend::LlamaCpp, &server.url()));
+        assert_eq!(detector.name(), "llamacpp:qwen2.5:7b");
+        let summary = detector
+            .summarize(&test_thread(&["rice", "football"]))
+            .await
+            .unwrap();
+        mock.assert_async().await;
+        assert_eq!(summary.topic, "cooking rice");
+        assert_eq!(summary.direction, "football");
+    }
+
+    #[tokio::test]
+    async fn test_failure() {
+        let mut server = Server::new_async().await;
+        server
+            .mock("POST", "/api/chat")
+            .with_status(404)
+            .with_body(r#"{"error": "model not found"}"#)
+            .create_async()
+            .await;
+        let detector = detector(settings(DetectorBackend::Ollama, &server.url()));
+        assert!(detector.locate(&test_thread(&["rice"]), 0).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_ping() {
+        let mut server = Server::new_async().await;
+        server
+            .mock("GET", "/api/tags")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"models": [{"name": "qwen2.5:7b"}, {"name": "llama3.2:latest"}]}"#)
+            .create_async()
+            .await;
+        let ping = |model: &str| {
+            let mut settings = settings(DetectorBackend::Ollama, &server.url());
+            settings.model = model.to_string();
+            detector(settings)
+        };
+        assert!(ping("qwen2.5:7b").ping().await.is_ok());
+        assert!(ping("llama3.2").ping().await.is_ok());
+        assert!(ping("mistral").ping().await.is_err());
+
+        let detector = detector(settings(DetectorBackend::LlamaCpp, &server.url()));
+        // no /v1/models is mocked
+        assert!(detector.ping().await.is_err());
+    }
+}
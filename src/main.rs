@@ -1,19 +1,39 @@
+mod account;
 mod api;
+mod bot;
+mod budget;
+mod cache;
+mod config;
+mod cost;
+mod crypto;
+mod daemon;
 mod data;
+mod detector;
+mod embedding;
+mod examples;
+mod history;
+mod inspect;
+mod local;
+mod metrics;
 mod openai;
+mod persona;
 mod post;
+mod server;
 mod session;
 mod util;
 
-use crate::data::SideTracker;
-use crate::openai::openai_locate_sidetracker;
+use crate::bot::Bot;
+use crate::config::{Config, Overrides};
+use crate::daemon::Daemon;
 use crate::post::PostLocator;
-use atrium_api::app::bsky::feed::post::RecordData;
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 use dotenv::dotenv;
-use log::debug;
-use std::collections::VecDeque;
 use std::error::Error;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing::level_filters::LevelFilter;
+use tracing::{debug, Instrument};
+use tracing_subscriber::EnvFilter;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -21,7 +41,7 @@ use std::error::Error;
 struct Cli {
     #[arg(short, long, action = clap::ArgAction::Count, default_value_t = 0, global = true)]
     /// verbosity of logging. This option can be repeated.
-    /// 0 - (Default) no overriding, use env RUST_LOG or pretty_env_logger's default level
+    /// 0 - (Default) no overriding, use env RUST_LOG or the default level Error
     /// 1 - Error,
     /// 2 - Warn,
     /// 3 - Info,
@@ -33,14 +53,33 @@ struct Cli {
     /// disable all logs.
     quiet: bool,
 
-    #[arg(short = 'n', long, global = true, env = "DRY_RUN")]
-    /// disable all post creation features.
-    dry_run: bool,
+    #[arg(long, value_enum, default_value_t = LogFormat::Text, global = true, env = "LOG_FORMAT")]
+    /// the format of the logs written to stderr.
+    log_format: LogFormat,
+
+    #[arg(short, long, global = true, env = "SIDETRACKER_CONFIG")]
+    /// the config file, defaults to $XDG_CONFIG_HOME/rust-sidetracker-bot/config.toml.
+    config: Option<PathBuf>,
+
+    #[arg(short, long, global = true, env = "PERSONA")]
+    /// the persona to use, defaults to the first configured account.
+    persona: Option<String>,
+
+    #[command(flatten)]
+    overrides: Overrides,
 
     #[command(subcommand)]
     command: Commands,
 }
 
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum LogFormat {
+    /// human readable lines
+    Text,
+    /// one JSON object per line, with the fields of the current span
+    Json,
+}
+
 #[derive(Subcommand, Debug)]
 enum Commands {
     /// check a thread and exit
@@ -48,67 +87,178 @@ enum Commands {
         /// thread uri
         thread: String,
     },
+    /// summarize what a thread was about and where it went, and reply with the root quoted
+    Summarize {
+        /// thread uri
+        thread: String,
+    },
+    /// check a thread and show how each post relates to the topic, without replying
+    Inspect {
+        /// thread uri
+        thread: String,
+    },
+    /// log in with an app password and save the session
+    Login,
+    /// revoke the saved session and remove it
+    Logout,
+    /// show the account of the saved session
+    Whoami,
+    /// watch the mentions of all personas and reply to them
+    Daemon,
+    /// inspect the configuration
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommands,
+    },
+    /// inspect or clear the cached verdicts
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommands,
+    },
+    /// show the outcomes of the recorded verdicts by experiment variant, prompt and model
+    Stats {
+        #[arg(long)]
+        /// only the verdicts of the last days
+        days: Option<u32>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigCommands {
+    /// print the effective configuration with secrets redacted
+    Show,
+}
+
+#[derive(Subcommand, Debug)]
+enum CacheCommands {
+    /// list the cached verdicts, the oldest first
+    List,
+    /// remove the cached verdicts
+    Clear {
+        #[arg(long)]
+        /// remove only the expired verdicts
+        expired: bool,
+    },
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     dotenv().ok();
-    pretty_env_logger::init();
 
     let cli = Cli::parse();
     set_verbosity(&cli);
     debug!("cli: {:?}", cli);
 
-    let reply = match cli.command {
-        Commands::Check { thread } => check(&PostLocator::from_url(&thread)?.at_uri()).await?,
-    };
-    if let Some(reply) = reply {
-        if cli.dry_run {
-            debug!("dry run: not posting");
-            println!("{}", serde_json::to_string_pretty(&reply).unwrap());
-        } else {
-            let agent = api::must_create_agent().await?;
-            debug!("posting reply: {:?}", reply);
-            let result = api::create_record(agent, reply).await?;
-            debug!("reply result: {:?}", result);
-            let locator = post::PostLocator::from_url(&result.uri);
-            debug!("reply result locator: {:?}", locator);
-            if locator.is_err() {
-                return Err(Box::from(locator.unwrap_err()));
+    let mut config = Config::load(cli.config.as_deref()).await?;
+    config.apply(&cli.overrides);
+    if let Commands::Config {
+        command: ConfigCommands::Show,
+    } = cli.command
+    {
+        print!("{}", toml::to_string_pretty(&config.redacted())?);
+        return Ok(());
+    }
+    if let Commands::Cache { ref command } = cli.command {
+        let verdict_cache = config.cache.verdict_cache();
+        match command {
+            CacheCommands::List => cache::print_entries(&verdict_cache).await?,
+            CacheCommands::Clear { expired } => {
+                let removed = verdict_cache.clear(*expired).await?;
+                println!("removed {} cached verdicts", removed);
             }
-            println!("reply published: {}", locator.unwrap().app_uri());
         }
+        return Ok(());
+    }
+    if let Commands::Stats { days } = cli.command {
+        history::print_stats(&config.history.history(), days).await?;
+        return Ok(());
     }
-    Ok(())
-}
-
-async fn check(thread: &str) -> Result<Option<RecordData>, Box<dyn Error>> {
-    let agent = api::must_create_agent().await?;
-    let res = api::get_post_thread(agent, thread.to_string()).await?;
-
-    let thread = post::FlattenedThread::from(&res);
-    let posts = VecDeque::from(&thread);
-    let result = SideTracker::new(
-        openai_locate_sidetracker(&posts).await,
-        thread.root.borrow().clone(),
-        thread.entrance.borrow().clone(),
-    );
 
-    debug!("side tracking result {:?}", result);
-    Ok(Some(result.build_reply()))
+    let session_key = config.session.secret_key().await?;
+    let personas = config.personas()?;
+    let persona = persona::select_persona(&personas, cli.persona.as_deref())?;
+    match cli.command {
+        Commands::Check { ref thread } => {
+            let bot = Bot::new(persona.clone(), &config, session_key.as_ref()).await?;
+            let thread = PostLocator::from_url(thread)?.at_uri();
+            async {
+                bot.ensure_budget().await?;
+                let result = bot.check(&thread).await?;
+                bot.publish(&result, config.dry_run).await
+            }
+            .instrument(bot.check_span(&thread, None))
+            .await?;
+        }
+        Commands::Summarize { ref thread } => {
+            let bot = Bot::new(persona.clone(), &config, session_key.as_ref()).await?;
+            let thread = PostLocator::from_url(thread)?.at_uri();
+            async {
+                bot.ensure_budget().await?;
+                let summary = bot.summarize(&thread).await?;
+                bot.publish_summary(&summary, config.dry_run).await
+            }
+            .instrument(bot.check_span(&thread, None))
+            .await?;
+        }
+        Commands::Inspect { ref thread } => {
+            let bot = Bot::new(persona.clone(), &config, session_key.as_ref()).await?;
+            let thread = PostLocator::from_url(thread)?.at_uri();
+            bot.ensure_budget().await?;
+            let result = bot
+                .check(&thread)
+                .instrument(bot.check_span(&thread, None))
+                .await?;
+            print!("{}", inspect::render_timeline(&result));
+        }
+        Commands::Login => account::login(persona, session_key.as_ref()).await?,
+        Commands::Logout => account::logout(persona, session_key.as_ref()).await?,
+        Commands::Whoami => account::whoami(persona, session_key.as_ref()).await?,
+        Commands::Daemon => {
+            let mut bots = Vec::with_capacity(personas.len());
+            for persona in personas {
+                bots.push(Bot::new(persona, &config, session_key.as_ref()).await?);
+            }
+            let daemon = Arc::new(Daemon::new(bots, &config));
+            if let Some(listen) = config.metrics.listen {
+                server::spawn(listen, server::router(daemon.clone())).await?;
+            }
+            daemon.run().await?;
+        }
+        Commands::Config { .. } | Commands::Cache { .. } | Commands::Stats { .. } => {
+            unreachable!()
+        }
+    }
+    Ok(())
 }
 
 fn set_verbosity(cli: &Cli) {
     let log_level = match (cli.quiet, cli.verbose) {
-        (true, _) => Some(log::LevelFilter::Off),
+        (true, _) => Some(LevelFilter::OFF),
         // keep default value, which can be set by env RUST_LOG
         (false, 0) => None,
-        (false, 1) => Some(log::LevelFilter::Error),
-        (false, 2) => Some(log::LevelFilter::Warn),
-        (false, 3) => Some(log::LevelFilter::Info),
-        (false, 4) => Some(log::LevelFilter::Debug),
-        (false, _) => Some(log::LevelFilter::Trace),
+        (false, 1) => Some(LevelFilter::ERROR),
+        (false, 2) => Some(LevelFilter::WARN),
+        (false, 3) => Some(LevelFilter::INFO),
+        (false, 4) => Some(LevelFilter::DEBUG),
+        (false, _) => Some(LevelFilter::TRACE),
     };
 
-    log_level.map(log::set_max_level);
+    let filter = match log_level {
+        Some(log_level) => EnvFilter::default().add_directive(log_level.into()),
+        None => EnvFilter::builder()
+            .with_default_directive(LevelFilter::ERROR.into())
+            .from_env_lossy(),
+    };
+    let logger = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+    match cli.log_format {
+        LogFormat::Text => logger.init(),
+        LogFormat::Json => logger
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(false)
+            .init(),
+    }
 }
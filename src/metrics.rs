@@ -0,0 +1,204 @@
+use atrium_xrpc::http::{Request, Response};
+use atrium_xrpc::{HttpClient, XrpcClient};
+use prometheus::{
+    exponential_buckets, CounterVec, Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter,
+    IntCounterVec, Opts, Registry, TextEncoder,
+};
+use std::sync::LazyLock;
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// What went wrong, as the label of the failure counter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureKind {
+    Poll,
+    FetchThread,
+    Detect,
+    Reply,
+}
+
+impl FailureKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FailureKind::Poll => "poll",
+            FailureKind::FetchThread => "fetch_thread",
+            FailureKind::Detect => "detect",
+            FailureKind::Reply => "reply",
+        }
+    }
+}
+
+pub struct Metrics {
+    registry: Registry,
+    pub summons: IntCounter,
+    pub checks: IntCounter,
+    pub verdicts: IntCounterVec,
+    pub replies: IntCounter,
+    pub failures: IntCounterVec,
+    pub thread_length: Histogram,
+    pub llm_latency: HistogramVec,
+    pub llm_tokens: HistogramVec,
+    pub llm_cost: CounterVec,
+    pub xrpc_latency: HistogramVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("sidetracker".to_string()), None).unwrap();
+        let summons = IntCounter::new("summons_total", "summons received").unwrap();
+        let checks = IntCounter::new("checks_total", "threads checked").unwrap();
+        let verdicts = IntCounterVec::new(
+            Opts::new(
+                "verdicts_total",
+                "check results, by whether a sidetrack is found",
+            ),
+            &["result"],
+        )
+        .unwrap();
+        let replies = IntCounter::new("replies_total", "replies posted").unwrap();
+        let failures = IntCounterVec::new(
+            Opts::new("failures_total", "failures by error kind"),
+            &["kind"],
+        )
+        .unwrap();
+        let thread_length = Histogram::with_opts(
+            HistogramOpts::new("thread_length", "posts in a checked thread")
+                .buckets(exponential_buckets(2.0, 2.0, 8).unwrap()),
+        )
+        .unwrap();
+        let llm_latency = HistogramVec::new(
+            HistogramOpts::new("llm_latency_seconds", "latency of LLM requests")
+                .buckets(exponential_buckets(0.25, 2.0, 9).unwrap()),
+            &["model"],
+        )
+        .unwrap();
+        let llm_tokens = HistogramVec::new(
+            HistogramOpts::new("llm_tokens", "tokens used by LLM requests")
+                .buckets(exponential_buckets(64.0, 2.0, 10).unwrap()),
+            &["model", "kind"],
+        )
+        .unwrap();
+        let llm_cost = CounterVec::new(
+            Opts::new("llm_cost_dollars_total", "cost of LLM requests in USD"),
+            &["model"],
+        )
+        .unwrap();
+        let xrpc_latency = HistogramVec::new(
+            HistogramOpts::new("xrpc_latency_seconds", "latency of XRPC requests")
+                .buckets(exponential_buckets(0.025, 2.0, 9).unwrap()),
+            &["method"],
+        )
+        .unwrap();
+
+        registry.register(Box::new(summons.clone())).unwrap();
+        registry.register(Box::new(checks.clone())).unwrap();
+        registry.register(Box::new(verdicts.clone())).unwrap();
+        registry.register(Box::new(replies.clone())).unwrap();
+        registry.register(Box::new(failures.clone())).unwrap();
+        registry.register(Box::new(thread_length.clone())).unwrap();
+        registry.register(Box::new(llm_latency.clone())).unwrap();
+        registry.register(Box::new(llm_tokens.clone())).unwrap();
+        registry.register(Box::new(llm_cost.clone())).unwrap();
+        registry.register(Box::new(xrpc_latency.clone())).unwrap();
+
+        Self {
+            registry,
+            summons,
+            checks,
+            verdicts,
+            replies,
+            failures,
+            thread_length,
+            llm_latency,
+            llm_tokens,
+            llm_cost,
+            xrpc_latency,
+        }
+    }
+
+    pub fn failure(&self, kind: FailureKind) {
+        self.failures.with_label_values(&[kind.as_str()]).inc();
+    }
+
+    pub fn verdict(&self, found: bool) {
+        let result = if found { "found" } else { "none" };
+        self.verdicts.with_label_values(&[result]).inc();
+    }
+
+    /// Render the metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+}
+
+/// The XRPC method of a request path, e.g. `app.bsky.feed.getPostThread`.
+fn xrpc_method(path: &str) -> &str {
+    path.strip_prefix("/xrpc/")
+        .filter(|nsid| !nsid.is_empty() && !nsid.contains('/'))
+        .unwrap_or("other")
+}
+
+/// Wraps an XRPC client to measure the latency of the requests.
+pub struct MeteredClient<T> {
+    inner: T,
+}
+
+impl<T> MeteredClient<T> {
+    pub fn new(inner: T) -> Self {
+        Self { inner }
+    }
+}
+
+impl<T: HttpClient + Send + Sync> HttpClient for MeteredClient<T> {
+    async fn send_http(
+        &self,
+        request: Request<Vec<u8>>,
+    ) -> Result<Response<Vec<u8>>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        let timer = METRICS
+            .xrpc_latency
+            .with_label_values(&[xrpc_method(request.uri().path())])
+            .start_timer();
+        let response = self.inner.send_http(request).await;
+        timer.observe_duration();
+        response
+    }
+}
+
+impl<T: XrpcClient + Send + Sync> XrpcClient for MeteredClient<T> {
+    fn base_uri(&self) -> String {
+        self.inner.base_uri()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_xrpc_method() {
+        assert_eq!(
+            xrpc_method("/xrpc/app.bsky.feed.getPostThread"),
+            "app.bsky.feed.getPostThread"
+        );
+        assert_eq!(xrpc_method("/xrpc/"), "other");
+        assert_eq!(xrpc_method("/xrpc/a/b"), "other");
+        assert_eq!(xrpc_method("/metrics"), "other");
+    }
+
+    #[test]
+    fn test_render() {
+        METRICS.summons.inc();
+        METRICS.verdict(true);
+        METRICS.failure(FailureKind::Detect);
+        METRICS.thread_length.observe(13.0);
+        let rendered = METRICS.render();
+        assert!(rendered.contains("sidetracker_summons_total"));
+        assert!(rendered.contains(r#"sidetracker_verdicts_total{result="found"}"#));
+        assert!(rendered.contains(r#"sidetracker_failures_total{kind="detect"}"#));
+        assert!(rendered.contains("sidetracker_thread_length_bucket"));
+    }
+}
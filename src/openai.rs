@@ -1,65 +1,666 @@
+use crate::budget::{self, Excerpt};
+use crate::config::OpenAiConfig;
+use crate::detector::{Derailment, Detector, Explanation, PaidFailure, Summary, Usage, Verdict};
+use crate::examples::{self, Example};
+use crate::metrics::METRICS;
+use crate::persona::DetectorSettings;
 use crate::post::Post;
 use crate::util;
-use log::{debug, error};
-use openai::chat::{ChatCompletion, ChatCompletionMessage, ChatCompletionMessageRole};
-use openai::Credentials;
-use std::collections::VecDeque;
-use std::env;
+use async_trait::async_trait;
+use atrium_api::types::string::Did;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::time::Duration;
+use tracing::debug;
 
-fn generate_prompt(thread: &VecDeque<Post>) -> String {
+pub const SUMMARY_PROMPT: &str = include_str!("../data/summary.txt");
+
+/// the alias of the author of the root post in the prompt
+const OP_ALIAS: &str = "OP";
+
+/// A post as a line of the prompt, with the alias of its author and how long after the previous
+/// post it was made, if known.
+fn post_line(idx: u32, author: Option<&str>, gap: Option<&str>, text: &str) -> String {
+    let mut line = idx.to_string();
+    if let Some(author) = author {
+        line.push_str(&format!(" [{}]", author));
+    }
+    if let Some(gap) = gap {
+        line.push_str(&format!(" +{}", gap));
+    }
+    format!("{}：{}\n", line, text.replace("\n", "\\n"))
+}
+
+/// How long after the previous post each post is made, by `idx`, for the posts whose times are
+/// known. A quick back and forth reads differently from a reply days later.
+fn reply_gaps(thread: &VecDeque<Post>) -> HashMap<u32, String> {
+    thread
+        .iter()
+        .zip(thread.iter().skip(1))
+        .filter_map(|(previous, post)| {
+            let gap = post.posted_at()? - previous.posted_at()?;
+            Some((post.idx, util::format_duration(gap)))
+        })
+        .collect()
+}
+
+/// A, B, ..., Z, then A2, B2, ...
+fn alias(n: usize) -> String {
+    let letter = (b'A' + (n % 26) as u8) as char;
+    match n / 26 {
+        0 => letter.to_string(),
+        round => format!("{}{}", letter, round + 1),
+    }
+}
+
+/// Short aliases of the authors, stable within the thread: `OP` for the original poster, then
+/// letters in the order the others first reply. Handles are left out of the prompt, as they may
+/// mislead the model and say nothing about the topic.
+fn author_aliases(thread: &VecDeque<Post>) -> HashMap<&Did, String> {
+    let mut aliases = HashMap::new();
+    for post in thread {
+        if aliases.contains_key(&post.did) {
+            continue;
+        }
+        let alias = if post.by_op {
+            OP_ALIAS.to_string()
+        } else {
+            alias(aliases.values().filter(|a| *a != OP_ALIAS).count())
+        };
+        aliases.insert(&post.did, alias);
+    }
+    aliases
+}
+
+/// The thread of an example, in the same shape as `generate_prompt`.
+fn example_prompt(example: &Example) -> String {
     let mut prompt = String::new();
     prompt.push_str("```\n");
-    for p in thread.iter() {
-        prompt.push_str(&format!("{}：{}\n", p.idx, p.text.replace("\n", "\\n")));
+    for (i, text) in example.posts.iter().enumerate() {
+        let author = example.authors.get(i).map(String::as_str);
+        prompt.push_str(&post_line(i as u32 + 1, author, None, text));
     }
     prompt.push_str("```\n");
     prompt
 }
 
-const OPENAI_MODEL_DEFAULT: &str = "gpt-4o-mini";
-
-pub async fn openai_locate_sidetracker(thread: &VecDeque<Post>) -> Option<Post> {
-    // Relies on OPENAI_KEY and optionally OPENAI_BASE_URL.
-    let credentials = Credentials::from_env();
-    let messages = vec![
-        ChatCompletionMessage {
-            role: ChatCompletionMessageRole::System,
-            content: Some(include_str!("../data/prompt.txt").to_string()),
-            ..Default::default()
-        },
-        ChatCompletionMessage {
-            role: ChatCompletionMessageRole::User,
-            content: Some(generate_prompt(thread)),
-            ..Default::default()
-        },
-    ];
-    let model = env::var("OPENAI_MODEL").unwrap_or(OPENAI_MODEL_DEFAULT.to_string());
-    debug!("using model {}", model);
-    let chat_completion = ChatCompletion::builder(&model, messages)
-        .credentials(credentials)
-        .create()
-        .await;
-    if let Ok(output) = chat_completion {
-        let returned_message = output.choices.first().unwrap().message.clone();
+fn generate_prompt(thread: &VecDeque<Post>, checked: usize, token_budget: usize) -> String {
+    let excerpts = budget::fit_thread(thread, checked, token_budget);
+    if excerpts.len() != thread.len() {
         debug!(
-            "OpenAI response: {:#?}: {}",
-            returned_message.role,
-            returned_message.content.clone().unwrap().trim()
+            "thread of {} posts is shortened to {} lines, {} posts are checked before",
+            thread.len(),
+            excerpts.len(),
+            checked
         );
-        let idx = util::find_and_parse_first_integer(
-            returned_message.content.clone().unwrap().trim().to_string(),
-        );
-        if let Some(idx) = idx {
-            for p in thread.iter() {
-                if p.idx == idx {
-                    return Some(p.clone());
-                }
+    }
+    let aliases = author_aliases(thread);
+    let gaps = reply_gaps(thread);
+    let mut prompt = String::new();
+    prompt.push_str("```\n");
+    for excerpt in excerpts {
+        match excerpt {
+            Excerpt::Post(p) => prompt.push_str(&post_line(
+                p.idx,
+                aliases.get(&p.did).map(String::as_str),
+                gaps.get(&p.idx).map(String::as_str),
+                &p.text,
+            )),
+            Excerpt::Elided { first, last } => {
+                prompt.push_str(&format!("[... posts {}-{} omitted ...]\n", first, last))
             }
+            Excerpt::Checked { first, last } => prompt.push_str(&format!(
+                "[... posts {}-{} omitted, none of them went off topic ...]\n",
+                first, last
+            )),
+        }
+    }
+    prompt.push_str("```\n");
+    prompt
+}
+
+/// The text after `label:` if the line starts with it, ignoring the case of the label.
+fn strip_label(line: &str, label: &str) -> Option<String> {
+    let head = line.get(..label.len())?;
+    if !head.eq_ignore_ascii_case(label) {
+        return None;
+    }
+    let rest = line[label.len()..].trim_start();
+    let rest = rest.strip_prefix(':').or_else(|| rest.strip_prefix('：'))?;
+    Some(rest.trim().to_string())
+}
+
+/// Split the answer into the index of the sidetracker and the explanation, if any.
+fn parse_answer(content: &str) -> (Option<u32>, Option<Explanation>) {
+    let mut topic = None;
+    let mut reason = None;
+    let mut rest = String::new();
+    for line in content.lines().map(str::trim) {
+        if let Some(text) = strip_label(line, "TOPIC") {
+            topic = Some(text);
+        } else if let Some(text) = strip_label(line, "REASON") {
+            reason = Some(text);
+        } else if strip_label(line, "RESCUER").is_some() {
+            continue;
+        } else {
+            rest.push_str(line);
+            rest.push('\n');
+        }
+    }
+    let idx = util::find_and_parse_first_integer(rest.trim().to_string());
+    let explanation = topic.map(|topic| Explanation {
+        topic,
+        reason: reason.unwrap_or_default(),
+    });
+    (idx, explanation)
+}
+
+/// The reply bringing the thread back on track, if the answer names one.
+fn parse_rescuer(content: &str) -> Option<u32> {
+    content
+        .lines()
+        .find_map(|line| strip_label(line.trim(), "RESCUER"))
+        .and_then(util::find_and_parse_first_integer)
+        .filter(|idx| *idx > 0)
+}
+
+/// The `number: topic` lines of the answer listing all the derail points.
+fn parse_derail_points(content: &str) -> Vec<(u32, Option<String>)> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| {
+            ["TOPIC", "REASON", "RESCUER"]
+                .iter()
+                .all(|label| strip_label(line, label).is_none())
+        })
+        .filter_map(|line| {
+            let digits = line
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(line.len());
+            let idx = line[..digits].parse().ok()?;
+            let topic = line[digits..]
+                .trim_start()
+                .trim_start_matches([':', '：', '.', '-'])
+                .trim();
+            Some((idx, (!topic.is_empty()).then(|| topic.to_string())))
+        })
+        .collect()
+}
+
+/// Who says a message of the chat.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ChatMessage {
+    pub role: Role,
+    pub content: String,
+}
+
+fn message(role: Role, content: String) -> ChatMessage {
+    ChatMessage { role, content }
+}
+
+/// The answer of a chat model, with the tokens used if the server tells.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Answer {
+    pub content: String,
+    pub prompt_tokens: Option<u64>,
+    pub completion_tokens: Option<u64>,
+}
+
+impl Answer {
+    /// The tokens taken, zero for those the server doesn't tell.
+    pub fn usage(&self) -> Usage {
+        Usage {
+            prompt_tokens: self.prompt_tokens.unwrap_or_default(),
+            completion_tokens: self.completion_tokens.unwrap_or_default(),
+        }
+    }
+}
+
+/// A chat model served over HTTP.
+#[async_trait]
+pub trait ChatModel: Send + Sync {
+    /// where the model is served, for the logs and the errors
+    fn url(&self) -> &str;
+
+    async fn chat(&self, model: &str, messages: &[ChatMessage]) -> Result<Answer, Box<dyn Error>>;
+
+    /// Check that the server is reachable and serves the model.
+    async fn ping(&self, model: &str) -> Result<(), Box<dyn Error>>;
+}
+
+/// The response if it is a success, or an error with what the server says.
+pub async fn success(response: reqwest::Response) -> Result<reqwest::Response, Box<dyn Error>> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let body = response.text().await.unwrap_or_default();
+    Err(format!("{}: {}", status, body.trim()).into())
+}
+
+#[derive(Serialize)]
+struct CompletionRequest<'a> {
+    model: &'a str,
+    messages: &'a [ChatMessage],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f64>,
+    stream: bool,
+}
+
+#[derive(Deserialize)]
+struct CompletionResponse {
+    choices: Vec<CompletionChoice>,
+    usage: Option<CompletionUsage>,
+}
+
+#[derive(Deserialize)]
+struct CompletionChoice {
+    message: AnswerMessage,
+}
+
+#[derive(Deserialize)]
+struct CompletionUsage {
+    prompt_tokens: u64,
+    completion_tokens: u64,
+}
+
+#[derive(Deserialize)]
+pub struct AnswerMessage {
+    pub content: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ModelList {
+    data: Vec<ModelEntry>,
+}
+
+#[derive(Deserialize)]
+struct ModelEntry {
+    id: String,
+}
+
+/// A client of an OpenAI compatible chat completions API, built once from the config with the
+/// key, the organization and the extra headers sent with every request. Only the answer and the
+/// usage are read from the responses, which other servers may give in a leaner shape.
+pub struct ChatClient {
+    client: reqwest::Client,
+    url: String,
+    temperature: Option<f64>,
+    any_model: bool,
+}
+
+impl ChatClient {
+    pub fn new(config: &OpenAiConfig) -> Result<Self, Box<dyn Error>> {
+        Self::with_env(config, |name| std::env::var(name).ok())
+    }
+
+    /// Build the client reading the API key with `env` instead of from the environment.
+    fn with_env(
+        config: &OpenAiConfig,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut headers = HeaderMap::new();
+        let key = Some(config.api_key_env.as_str())
+            .filter(|name| !name.is_empty())
+            .and_then(env)
+            .filter(|key| !key.is_empty());
+        if let Some(key) = key {
+            let mut value = HeaderValue::from_str(&format!("Bearer {}", key))
+                .map_err(|_| format!("invalid API key in {}", config.api_key_env))?;
+            value.set_sensitive(true);
+            headers.insert(AUTHORIZATION, value);
+        }
+        if let Some(ref organization) = config.organization {
+            headers.insert(
+                "OpenAI-Organization",
+                HeaderValue::from_str(organization)
+                    .map_err(|err| format!("invalid organization {}: {}", organization, err))?,
+            );
+        }
+        for (name, value) in &config.headers {
+            let invalid = |err: &dyn Error| format!("invalid header {}: {}", name, err);
+            let name = HeaderName::from_bytes(name.as_bytes()).map_err(|err| invalid(&err))?;
+            let mut value = HeaderValue::from_str(value).map_err(|err| invalid(&err))?;
+            value.set_sensitive(true);
+            headers.insert(name, value);
+        }
+        let client = reqwest::Client::builder()
+            .default_headers(headers)
+            .timeout(Duration::from_secs(config.timeout))
+            .connect_timeout(Duration::from_secs(config.connect_timeout))
+            .build()?;
+        Ok(Self {
+            client,
+            url: config.base_url.trim_end_matches('/').to_string(),
+            temperature: None,
+            any_model: false,
+        })
+    }
+
+    /// Sample at this temperature instead of the default of the server.
+    pub fn with_temperature(self, temperature: f64) -> Self {
+        Self {
+            temperature: Some(temperature),
+            ..self
+        }
+    }
+
+    /// Don't look for the model when pinging, as the server serves the one model it is started
+    /// with, whatever it is called.
+    pub fn serving_any_model(self) -> Self {
+        Self {
+            any_model: true,
+            ..self
         }
-        None
+    }
+}
+
+#[async_trait]
+impl ChatModel for ChatClient {
+    fn url(&self) -> &str {
+        &self.url
+    }
+
+    async fn chat(&self, model: &str, messages: &[ChatMessage]) -> Result<Answer, Box<dyn Error>> {
+        let response = self
+            .client
+            .post(format!("{}/chat/completions", self.url))
+            .json(&CompletionRequest {
+                model,
+                messages,
+                temperature: self.temperature,
+                stream: false,
+            })
+            .send()
+            .await?;
+        let response = success(response).await?;
+        let response: CompletionResponse = response.json().await?;
+        let choice = response
+            .choices
+            .into_iter()
+            .next()
+            .ok_or("no choice in the answer")?;
+        Ok(Answer {
+            content: choice.message.content.unwrap_or_default(),
+            prompt_tokens: response.usage.as_ref().map(|u| u.prompt_tokens),
+            completion_tokens: response.usage.as_ref().map(|u| u.completion_tokens),
+        })
+    }
+
+    async fn ping(&self, model: &str) -> Result<(), Box<dyn Error>> {
+        let response = self
+            .client
+            .get(format!("{}/models", self.url))
+            .send()
+            .await
+            .map_err(|err| format!("{} is unavailable: {}", self.url, err))?;
+        let models: ModelList = success(response)
+            .await
+            .map_err(|err| format!("{} is unavailable: {}", self.url, err))?
+            .json()
+            .await?;
+        if !self.any_model && !models.data.iter().any(|m| m.id == model) {
+            return Err(format!("model {} is not served by {}", model, self.url).into());
+        }
+        Ok(())
+    }
+}
+
+/// The system prompt, the examples as prior turns of the chat, then the thread, which takes what
+/// is left of the token budget.
+pub fn build_messages(
+    thread: &VecDeque<Post>,
+    checked: usize,
+    prompt: &str,
+    examples: &[&Example],
+    token_budget: usize,
+) -> Vec<ChatMessage> {
+    let mut messages = vec![message(Role::System, prompt.to_string())];
+    let mut used = budget::estimate_tokens(prompt);
+    for example in examples {
+        let question = example_prompt(example);
+        used += budget::estimate_tokens(&question) + 1;
+        messages.push(message(Role::User, question));
+        messages.push(message(Role::Assistant, example.answer.to_string()));
+    }
+    messages.push(message(
+        Role::User,
+        generate_prompt(thread, checked, token_budget.saturating_sub(used)),
+    ));
+    messages
+}
+
+/// Send the thread to the model with the system prompt, and return the answer with the tokens
+/// it took.
+async fn complete(
+    client: &dyn ChatModel,
+    thread: &VecDeque<Post>,
+    checked: usize,
+    prompt: &str,
+    examples: &[&Example],
+    detector: &DetectorSettings,
+) -> Result<(String, Usage), Box<dyn Error>> {
+    let model = detector.model.as_str();
+    let messages = build_messages(thread, checked, prompt, examples, detector.token_budget);
+    debug!("using model {} of {}", model, client.url());
+    let timer = METRICS
+        .llm_latency
+        .with_label_values(&[model])
+        .start_timer();
+    let answer = client.chat(model, &messages).await;
+    timer.observe_duration();
+    let answer =
+        answer.map_err(|err| format!("detector request to {} failed: {}", client.url(), err))?;
+    for (kind, tokens) in [
+        ("prompt", answer.prompt_tokens),
+        ("completion", answer.completion_tokens),
+    ] {
+        if let Some(tokens) = tokens {
+            METRICS
+                .llm_tokens
+                .with_label_values(&[model, kind])
+                .observe(tokens as f64);
+        }
+    }
+    debug!("{} response: {}", client.url(), answer.content.trim());
+    let usage = answer.usage();
+    Ok((answer.content, usage))
+}
+
+/// The examples most relevant to the thread. They answer with a bare number, so none is shown
+/// when the answer is asked in another shape.
+pub fn select_examples<'a>(
+    thread: &VecDeque<Post>,
+    examples: &'a [Example],
+    detector: &DetectorSettings,
+) -> Vec<&'a Example> {
+    if detector.explain || detector.max_derailments > 1 || detector.find_rescuer {
+        return Vec::new();
+    }
+    let lang = examples::detect_lang(thread.iter().map(|p| p.text.as_str()));
+    examples::select(examples, lang, thread.len(), detector.few_shot.count)
+}
+
+/// Find the sidetracker of the thread. The first `checked` posts were judged on topic before, so
+/// only the posts after them can be the answer. The most relevant of the `examples` are shown to
+/// the model first.
+pub async fn openai_locate_sidetracker(
+    client: &dyn ChatModel,
+    thread: &VecDeque<Post>,
+    checked: usize,
+    prompt: &str,
+    examples: &[Example],
+    detector: &DetectorSettings,
+) -> Result<Verdict, Box<dyn Error>> {
+    let examples = select_examples(thread, examples, detector);
+    let (content, usage) = complete(client, thread, checked, prompt, &examples, detector).await?;
+    Ok(Verdict {
+        usage,
+        ..parse_verdict(thread, checked, &content, detector)
+    })
+}
+
+/// The verdict told by the answer of a chat model, keeping only what is asked for.
+pub fn parse_verdict(
+    thread: &VecDeque<Post>,
+    checked: usize,
+    content: &str,
+    detector: &DetectorSettings,
+) -> Verdict {
+    let (idx, explanation) = parse_answer(content);
+    let points = if detector.max_derailments > 1 {
+        parse_derail_points(content)
     } else {
-        error!("error: {:#?}", chat_completion);
-        None
+        idx.map(|idx| (idx, None)).into_iter().collect()
+    };
+    let mut derailments: Vec<Derailment> = Vec::new();
+    for (idx, topic) in points {
+        if derailments.len() >= detector.max_derailments {
+            break;
+        }
+        if derailments.iter().any(|d| d.post.idx == idx) {
+            continue;
+        }
+        if let Some(post) = thread.iter().skip(checked).find(|p| p.idx == idx) {
+            derailments.push(Derailment {
+                post: post.clone(),
+                topic,
+            });
+        }
+    }
+    derailments.sort_by_key(|d| d.post.idx);
+    let rescuer = derailments
+        .first()
+        .filter(|_| detector.find_rescuer)
+        .and_then(|sidetracker| {
+            let idx = parse_rescuer(content)?;
+            thread
+                .iter()
+                .find(|p| p.idx == idx && p.idx > sidetracker.post.idx)
+                .cloned()
+        });
+    Verdict {
+        rescuer,
+        explanation: explanation
+            .filter(|_| detector.explain)
+            .map(|explanation| Explanation {
+                // nobody to blame, even if the model gave a reason
+                reason: if !derailments.is_empty() {
+                    explanation.reason
+                } else {
+                    String::new()
+                },
+                ..explanation
+            }),
+        derailments,
+        ..Default::default()
+    }
+}
+
+/// Split the answer into the topic and where the thread went.
+pub fn parse_summary(content: &str) -> Result<Summary, Box<dyn Error>> {
+    let mut topic = None;
+    let mut direction = None;
+    for line in content.lines().map(str::trim) {
+        if let Some(text) = strip_label(line, "TOPIC") {
+            topic = Some(text);
+        } else if let Some(text) = strip_label(line, "NOW") {
+            direction = Some(text);
+        }
+    }
+    match (topic, direction) {
+        (Some(topic), Some(direction)) => Ok(Summary {
+            topic,
+            direction,
+            ..Default::default()
+        }),
+        _ => Err(format!("unexpected summary from the detector: {}", content.trim()).into()),
+    }
+}
+
+/// Summarize the topic of the thread and where the conversation went.
+pub async fn openai_summarize(
+    client: &dyn ChatModel,
+    thread: &VecDeque<Post>,
+    detector: &DetectorSettings,
+) -> Result<Summary, Box<dyn Error>> {
+    let (content, usage) = complete(client, thread, 0, SUMMARY_PROMPT, &[], detector).await?;
+    let summary = parse_summary(&content).map_err(|error| PaidFailure { usage, error })?;
+    Ok(Summary { usage, ..summary })
+}
+
+/// Asks a chat model for the sidetracker.
+pub struct OpenAiDetector {
+    client: Box<dyn ChatModel>,
+    name: String,
+    settings: DetectorSettings,
+    prompt: String,
+    examples: Vec<Example>,
+}
+
+impl OpenAiDetector {
+    pub fn new(client: Box<dyn ChatModel>, settings: DetectorSettings, prompt: String) -> Self {
+        Self {
+            client,
+            name: settings.model.clone(),
+            settings,
+            prompt,
+            examples: Vec::new(),
+        }
+    }
+
+    /// Name the detector other than by the model, e.g. to tell the backend serving it.
+    pub fn with_name(self, name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            ..self
+        }
+    }
+
+    pub fn with_examples(self, examples: Vec<Example>) -> Self {
+        Self { examples, ..self }
+    }
+}
+
+#[async_trait]
+impl Detector for OpenAiDetector {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    async fn locate(
+        &self,
+        thread: &VecDeque<Post>,
+        checked: usize,
+    ) -> Result<Verdict, Box<dyn Error>> {
+        openai_locate_sidetracker(
+            self.client.as_ref(),
+            thread,
+            checked,
+            &self.prompt,
+            &self.examples,
+            &self.settings,
+        )
+        .await
+    }
+
+    async fn summarize(&self, thread: &VecDeque<Post>) -> Result<Summary, Box<dyn Error>> {
+        openai_summarize(self.client.as_ref(), thread, &self.settings).await
+    }
+
+    async fn ping(&self) -> Result<(), Box<dyn Error>> {
+        self.client.ping(&self.settings.model).await
     }
 }
 
@@ -70,28 +671,310 @@ mod tests {
     use atrium_api::types::string::{Cid, Did};
 
     use super::*;
-    use crate::post::Post;
+    use crate::persona::Persona;
+    use crate::post::{test_post, test_thread, Post};
+    use mockito::Matcher::{self, PartialJsonString};
+    use mockito::Server;
+
+    fn openai_config(url: &str) -> OpenAiConfig {
+        OpenAiConfig {
+            base_url: format!("{}/v1/", url),
+            api_key_env: "SIDETRACKER_TEST_OPENAI_KEY".to_string(),
+            organization: Some("org-test".to_string()),
+            headers: [("X-Gateway-Key".to_string(), "gateway".to_string())].into(),
+            ..Default::default()
+        }
+    }
 
     #[test]
     fn test_generate_prompt() {
         let mut thread = VecDeque::new();
         thread.push_back(Post {
-            cid: Cid::from_str("bafyreihvgtbjqmyo2ocpfic3rgjtvepbopaaaaawcccccsxxxxxw3nnjly").unwrap(),
+            cid: Cid::from_str("bafyreihvgtbjqmyo2ocpfic3rgjtvepbopaaaaawcccccsxxxxxw3nnjly")
+                .unwrap(),
             did: Did::from_str("did:plc:fkjudld5cgxxxxxxxxxxxxxx").unwrap(),
             handle: "user-1".to_string(),
             idx: 1,
             text: "Hello".to_string(),
             uri: "at://uri1".to_string(),
+            by_op: true,
+            quoted: false,
+            created_at: Some("2024-12-27T03:53:36Z".parse().unwrap()),
+            indexed_at: None,
         });
         thread.push_back(Post {
-            cid: Cid::from_str("bafyreihvgtbjqmyo2ocpfic3rgjtvepbopbbbbbwaaaaasyyyyyw3nnjly").unwrap(),
+            cid: Cid::from_str("bafyreihvgtbjqmyo2ocpfic3rgjtvepbopbbbbbwaaaaasyyyyyw3nnjly")
+                .unwrap(),
             did: Did::from_str("did:plc:fkjudld5cgyyyyyyyyyyyyyy").unwrap(),
             handle: "user-2".to_string(),
             idx: 2,
             text: "World".to_string(),
             uri: "at://uri2".to_string(),
+            by_op: false,
+            quoted: false,
+            created_at: Some("2024-12-27T03:58:50Z".parse().unwrap()),
+            indexed_at: None,
         });
-        let prompt = generate_prompt(&thread);
-        assert_eq!(prompt, "```\n1：Hello\n2：World\n```\n");
+        let prompt = generate_prompt(&thread, 0, 1000);
+        assert_eq!(prompt, "```\n1 [OP]：Hello\n2 [A] +5m：World\n```\n");
+
+        thread.push_back(Post {
+            idx: 3,
+            text: "Bye".to_string(),
+            ..thread[1].clone()
+        });
+        thread.push_back(Post {
+            idx: 4,
+            text: "!".to_string(),
+            created_at: None,
+            ..thread[1].clone()
+        });
+        let prompt = generate_prompt(&thread, 0, 0);
+        assert_eq!(
+            prompt,
+            "```\n1 [OP]：Hello\n[... posts 2-3 omitted ...]\n4 [A]：!\n```\n"
+        );
+    }
+
+    #[test]
+    fn test_author_aliases() {
+        let thread: VecDeque<Post> = ["b", "op", "c", "b"]
+            .iter()
+            .zip(1..)
+            .map(|(handle, idx)| Post {
+                by_op: *handle == "op",
+                ..test_post(idx, handle, "text")
+            })
+            .collect();
+        let aliases = author_aliases(&thread);
+        let alias_of = |i: usize| aliases[&thread[i].did].as_str();
+        assert_eq!(
+            (alias_of(0), alias_of(1), alias_of(2), alias_of(3)),
+            ("A", "OP", "B", "A")
+        );
+        assert_eq!(alias(25), "Z");
+        assert_eq!(alias(26), "A2");
+    }
+
+    #[test]
+    fn test_build_messages() {
+        let thread = test_thread(&["Hello", "World"]);
+        let example = Example {
+            lang: "en".to_string(),
+            posts: vec!["rice?".to_string(), "football!".to_string()],
+            authors: vec!["OP".to_string(), "A".to_string()],
+            answer: 2,
+        };
+        let messages = build_messages(&thread, 0, "prompt", &[&example], 1000);
+        let turns: Vec<(Role, &str)> = messages
+            .iter()
+            .map(|m| (m.role, m.content.as_str()))
+            .collect();
+        assert_eq!(
+            turns,
+            vec![
+                (Role::System, "prompt"),
+                (Role::User, "```\n1 [OP]：rice?\n2 [A]：football!\n```\n"),
+                (Role::Assistant, "2"),
+                (Role::User, "```\n1 [A]：Hello\n2 [A]：World\n```\n"),
+            ]
+        );
+
+        let mut settings = Persona::new("test", "test.handle").detector;
+        let examples = vec![
+            Example {
+                lang: "zh".to_string(),
+                ..example.clone()
+            },
+            example,
+        ];
+        let selected = select_examples(&thread, &examples, &settings);
+        assert_eq!(selected.len(), 2);
+        assert_eq!(selected[0].lang, "en");
+        settings.explain = true;
+        assert!(select_examples(&thread, &examples, &settings).is_empty());
+    }
+
+    #[test]
+    fn test_parse_answer() {
+        assert_eq!(parse_answer(" 3 "), (Some(3), None));
+        assert_eq!(
+            parse_answer("5\nTOPIC: how to cook rice, 2 cups\nreason：it talks about football"),
+            (
+                Some(5),
+                Some(Explanation {
+                    topic: "how to cook rice, 2 cups".to_string(),
+                    reason: "it talks about football".to_string(),
+                })
+            )
+        );
+        assert_eq!(
+            parse_answer("Topic: rice\n0"),
+            (
+                Some(0),
+                Some(Explanation {
+                    topic: "rice".to_string(),
+                    reason: String::new(),
+                })
+            )
+        );
+        assert_eq!(strip_label("TOPICS: x", "TOPIC"), None);
+        assert_eq!(strip_label("话题：x", "TOPIC"), None);
+    }
+
+    #[test]
+    fn test_parse_derail_points() {
+        assert_eq!(
+            parse_derail_points("5: football\n9：猫\nTOPIC: rice\n12\nnone"),
+            vec![
+                (5, Some("football".to_string())),
+                (9, Some("猫".to_string())),
+                (12, None)
+            ]
+        );
+        assert_eq!(parse_derail_points("0"), vec![(0, None)]);
+        assert_eq!(
+            parse_derail_points("5: football\nRESCUER: 7"),
+            vec![(5, Some("football".to_string()))]
+        );
+    }
+
+    #[test]
+    fn test_parse_rescuer() {
+        assert_eq!(parse_answer("5\nRESCUER: 7"), (Some(5), None));
+        assert_eq!(parse_rescuer("5\nRESCUER: 7"), Some(7));
+        assert_eq!(parse_rescuer("5\nrescuer：0"), None);
+        assert_eq!(parse_rescuer("5"), None);
+    }
+
+    #[test]
+    fn test_parse_summary() {
+        assert_eq!(
+            parse_summary("TOPIC: how to cook rice\nNOW：last night's football match").unwrap(),
+            Summary {
+                topic: "how to cook rice".to_string(),
+                direction: "last night's football match".to_string(),
+                ..Default::default()
+            }
+        );
+        assert!(parse_summary("TOPIC: how to cook rice").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_chat_client() {
+        let mut server = Server::new_async().await;
+        let mock = server
+            .mock("POST", "/v1/chat/completions")
+            .match_header("authorization", "Bearer sk-test")
+            .match_header("openai-organization", "org-test")
+            .match_header("x-gateway-key", "gateway")
+            .match_body(PartialJsonString(
+                r#"{"model": "gpt-4o-mini", "stream": false}"#.to_string(),
+            ))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"{"id": "chatcmpl-1", "object": "chat.completion", "created": 1735228995,
+                    "model": "gpt-4o-mini", "choices": [{"index": 0, "finish_reason": "stop",
+                    "message": {"role": "assistant", "content": "2"}}],
+                    "usage": {"prompt_tokens": 80, "completion_tokens": 1, "total_tokens": 81}}"#,
+            )
+            .create_async()
+            .await;
+        let env =
+            |name: &str| (name == "SIDETRACKER_TEST_OPENAI_KEY").then(|| "sk-test".to_string());
+        let client = ChatClient::with_env(&openai_config(&server.url()), env).unwrap();
+        let settings = Persona::new("test", "test.handle").detector;
+        let detector = OpenAiDetector::new(Box::new(client), settings, "prompt".to_string());
+        assert_eq!(detector.name(), "gpt-4o-mini");
+        let thread = test_thread(&["rice", "football"]);
+        let verdict = detector.locate(&thread, 0).await.unwrap();
+        mock.assert_async().await;
+        assert_eq!(verdict.sidetracker().unwrap().idx, 2);
+        assert_eq!(
+            verdict.usage,
+            Usage {
+                prompt_tokens: 80,
+                completion_tokens: 1,
+            }
+        );
+
+        // another configuration in the same process, with no key nor organization
+        let mut other = Server::new_async().await;
+        let mock = other
+            .mock("POST", "/v1/chat/completions")
+            .match_header("authorization", Matcher::Missing)
+            .match_header("openai-organization", Matcher::Missing)
+            .with_status(401)
+            .with_body(r#"{"error": {"code": "invalid_api_key"}}"#)
+            .create_async()
+            .await;
+        let config = OpenAiConfig {
+            base_url: format!("{}/v1", other.url()),
+            api_key_env: String::new(),
+            ..Default::default()
+        };
+        let client = ChatClient::with_env(&config, env).unwrap();
+        let messages = [message(Role::User, "rice".to_string())];
+        let err = client.chat("gpt-4o-mini", &messages).await.unwrap_err();
+        mock.assert_async().await;
+        assert!(err.to_string().contains("invalid_api_key"));
+    }
+
+    #[tokio::test]
+    async fn test_summary_failure_usage() {
+        let mut server = Server::new_async().await;
+        server
+            .mock("POST", "/v1/chat/completions")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"{"choices": [{"index": 0, "message": {"role": "assistant",
+                    "content": "no idea"}}],
+                    "usage": {"prompt_tokens": 80, "completion_tokens": 2, "total_tokens": 82}}"#,
+            )
+            .create_async()
+            .await;
+        let client = ChatClient::with_env(&openai_config(&server.url()), |_| None).unwrap();
+        let settings = Persona::new("test", "test.handle").detector;
+        let detector = OpenAiDetector::new(Box::new(client), settings, "prompt".to_string());
+        let err = detector
+            .summarize(&test_thread(&["rice", "football"]))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            Usage::of_failure(err.as_ref()),
+            Usage {
+                prompt_tokens: 80,
+                completion_tokens: 2,
+            }
+        );
+
+        // no answer, nothing paid
+        let err: Box<dyn Error> = "connection refused".into();
+        assert_eq!(Usage::of_failure(err.as_ref()), Usage::default());
+    }
+
+    #[tokio::test]
+    async fn test_chat_client_ping() {
+        let mut server = Server::new_async().await;
+        server
+            .mock("GET", "/v1/models")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"object": "list", "data": [{"id": "gpt-4o-mini", "object": "model"}]}"#)
+            .create_async()
+            .await;
+        let client = ChatClient::new(&openai_config(&server.url())).unwrap();
+        assert!(client.ping("gpt-4o-mini").await.is_ok());
+        assert!(client.ping("o1").await.is_err());
+        let client = client.serving_any_model();
+        assert!(client.ping("o1").await.is_ok());
+
+        let mut config = openai_config(&server.url());
+        config
+            .headers
+            .insert("bad header".to_string(), String::new());
+        assert!(ChatClient::new(&config).is_err());
     }
 }
@@ -0,0 +1,396 @@
+use crate::api::SessionOptions;
+use crate::budget;
+use crate::config::{DetectorBackend, EmbeddingConfig, FewShotConfig, LocalConfig, OpenAiConfig};
+use crate::crypto::SecretKey;
+use crate::data::ReplyTemplate;
+use crate::examples::{self, Example};
+use atrium_api::types::string::Did;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::env;
+use std::error::Error;
+use std::path::PathBuf;
+
+pub const DEFAULT_PERSONA: &str = "default";
+pub const DEFAULT_PASSWORD_ENV: &str = "BLUESKY_PASSWORD";
+/// the variant name of a persona with no experiment
+pub const DEFAULT_VARIANT: &str = "default";
+/// the id of the built-in prompt
+const DEFAULT_PROMPT_ID: &str = "builtin";
+const DEFAULT_PROMPT: &str = include_str!("../data/prompt.txt");
+/// tells how the authors of the replies are shown
+const AUTHORS_PROMPT: &str = include_str!("../data/authors.txt");
+/// tells that the original poster never sidetracks
+const SPARE_OP_PROMPT: &str = include_str!("../data/spare_op.txt");
+/// asks for the topic and the reason along with the answer, in explain mode
+const EXPLAIN_PROMPT: &str = include_str!("../data/explain.txt");
+/// asks for all the derail points instead of the first, when more than one are wanted
+const MULTI_PROMPT: &str = include_str!("../data/multi.txt");
+/// asks for who brought the thread back on track
+const RESCUER_PROMPT: &str = include_str!("../data/rescuer.txt");
+
+/// A bot account together with how it talks and judges threads.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Persona {
+    /// the name to select the persona with
+    pub name: String,
+    /// handle, DID or email of the account
+    pub identifier: String,
+    /// the entryway to log in
+    pub service: String,
+    /// the env var holding the app password, used when there is no usable saved session
+    pub password_env: String,
+    /// overrides the per-account session file
+    pub session_file: Option<PathBuf>,
+    /// the system prompt file, the built-in prompt is used if not set
+    pub prompt_file: Option<PathBuf>,
+    /// the id of the prompt recorded with the verdicts, named after the prompt file if not set
+    pub prompt_id: Option<String>,
+    pub reply: ReplyTemplate,
+    /// handles or DIDs allowed to summon the bot in daemon mode, everyone is allowed when empty
+    pub allowlist: Vec<String>,
+    pub detector: DetectorSettings,
+    /// the prompt or model variants the summons are split between, only the prompt and the
+    /// model above are used when empty
+    pub variants: Vec<Variant>,
+}
+
+/// A prompt and model to judge threads with, picked for a summon by its weight in an experiment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Variant {
+    pub name: String,
+    pub weight: u32,
+    /// the system prompt file, the built-in prompt is used if not set
+    pub prompt_file: Option<PathBuf>,
+    /// the id of the prompt recorded with the verdicts, named after the prompt file if not set
+    pub prompt_id: Option<String>,
+    pub detector: DetectorSettings,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DetectorSettings {
+    pub backend: DetectorBackend,
+    pub model: String,
+    /// how many tokens the prompt may take, the thread is truncated to fit
+    pub token_budget: usize,
+    pub embedding: EmbeddingConfig,
+    pub local: LocalConfig,
+    pub openai: OpenAiConfig,
+    /// ask for the topic and why the sidetracker derails it
+    pub explain: bool,
+    /// how many derail points are reported, only the first one if 1
+    pub max_derailments: usize,
+    /// also find who brought the thread back to the topic
+    pub find_rescuer: bool,
+    pub few_shot: FewShotConfig,
+    /// never blame the original poster for changing the subject of their own thread
+    pub spare_op: bool,
+}
+
+impl Persona {
+    pub fn new(name: impl Into<String>, identifier: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            identifier: identifier.into(),
+            service: "https://bsky.social".to_string(),
+            password_env: DEFAULT_PASSWORD_ENV.to_string(),
+            session_file: None,
+            prompt_file: None,
+            prompt_id: None,
+            reply: ReplyTemplate::default(),
+            allowlist: Vec::new(),
+            detector: DetectorSettings {
+                backend: DetectorBackend::default(),
+                model: "gpt-4o-mini".to_string(),
+                token_budget: budget::default_token_budget("gpt-4o-mini"),
+                embedding: EmbeddingConfig::default(),
+                local: LocalConfig::default(),
+                openai: OpenAiConfig::default(),
+                explain: false,
+                max_derailments: 1,
+                find_rescuer: false,
+                few_shot: FewShotConfig::default(),
+                spare_op: false,
+            },
+            variants: Vec::new(),
+        }
+    }
+
+    pub fn session_options(&self, session_key: Option<&SecretKey>) -> SessionOptions {
+        SessionOptions {
+            session_file: self.session_file.clone(),
+            session_key: session_key.cloned(),
+        }
+    }
+
+    pub fn password(&self) -> Option<String> {
+        env::var(&self.password_env).ok()
+    }
+
+    /// The variants of the experiment, or the prompt and the model of the persona alone.
+    pub fn variants(&self) -> Vec<Variant> {
+        if !self.variants.is_empty() {
+            return self.variants.clone();
+        }
+        vec![Variant {
+            name: DEFAULT_VARIANT.to_string(),
+            weight: 1,
+            prompt_file: self.prompt_file.clone(),
+            prompt_id: self.prompt_id.clone(),
+            detector: self.detector.clone(),
+        }]
+    }
+
+    /// Whether the user is allowed to summon this persona.
+    pub fn allows(&self, did: &Did, handle: &str) -> bool {
+        self.allowlist.is_empty()
+            || self
+                .allowlist
+                .iter()
+                .any(|allowed| allowed == did.as_str() || allowed.eq_ignore_ascii_case(handle))
+    }
+}
+
+impl Variant {
+    /// The id of the prompt: the configured one, the name of the prompt file or `builtin`.
+    pub fn prompt_id(&self) -> String {
+        if let Some(ref id) = self.prompt_id {
+            return id.clone();
+        }
+        self.prompt_file
+            .as_ref()
+            .and_then(|path| path.file_stem())
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_else(|| DEFAULT_PROMPT_ID.to_string())
+    }
+
+    /// The system prompt, with the explain instruction appended in explain mode.
+    pub async fn load_prompt(&self) -> Result<String, Box<dyn Error>> {
+        let mut prompt = match self.prompt_file {
+            Some(ref path) => tokio::fs::read_to_string(path)
+                .await
+                .map_err(|err| format!("failed to read prompt file {}: {}", path.display(), err))?,
+            None => DEFAULT_PROMPT.to_string(),
+        };
+        prompt.push_str(AUTHORS_PROMPT);
+        if self.detector.spare_op {
+            prompt.push_str(SPARE_OP_PROMPT);
+        }
+        if self.detector.max_derailments > 1 {
+            prompt.push_str(
+                &MULTI_PROMPT.replace("{max}", &self.detector.max_derailments.to_string()),
+            );
+        }
+        if self.detector.explain {
+            prompt.push_str(EXPLAIN_PROMPT);
+        }
+        if self.detector.find_rescuer {
+            prompt.push_str(RESCUER_PROMPT);
+        }
+        Ok(prompt)
+    }
+
+    /// The example bank of the detector, empty if the examples are turned off.
+    pub async fn load_examples(&self) -> Result<Vec<Example>, Box<dyn Error>> {
+        let few_shot = &self.detector.few_shot;
+        if !few_shot.enabled || few_shot.count == 0 {
+            return Ok(Vec::new());
+        }
+        examples::load_examples(few_shot.file.as_deref()).await
+    }
+}
+
+pub fn validate_personas(personas: &[Persona]) -> Result<(), Box<dyn Error>> {
+    if personas.is_empty() {
+        return Err("no persona is configured".into());
+    }
+    let mut names = HashSet::new();
+    let mut identifiers = HashSet::new();
+    for persona in personas {
+        if !names.insert(persona.name.as_str()) {
+            return Err(format!("duplicated persona name {}", persona.name).into());
+        }
+        if !identifiers.insert(persona.identifier.as_str()) {
+            return Err(format!("duplicated persona account {}", persona.identifier).into());
+        }
+        validate_variants(&persona.variants)?;
+    }
+    Ok(())
+}
+
+fn validate_variants(variants: &[Variant]) -> Result<(), Box<dyn Error>> {
+    let mut names = HashSet::new();
+    for variant in variants {
+        if !names.insert(variant.name.as_str()) {
+            return Err(format!("duplicated experiment variant {}", variant.name).into());
+        }
+    }
+    if !variants.is_empty() && variants.iter().all(|v| v.weight == 0) {
+        return Err("all experiment variants weigh 0".into());
+    }
+    Ok(())
+}
+
+/// Pick a variant by weight for the summon. The pick looks random across summons, while the same
+/// summon always gets the same variant, so retries and cached verdicts stay consistent.
+pub fn assign_variant(weights: &[u32], summon: &str) -> usize {
+    let total: u64 = weights.iter().map(|&w| w as u64).sum();
+    if total == 0 {
+        return 0;
+    }
+    let digest = Sha256::digest(summon.as_bytes());
+    let mut point = u64::from_be_bytes(digest[..8].try_into().unwrap()) % total;
+    for (idx, &weight) in weights.iter().enumerate() {
+        if point < weight as u64 {
+            return idx;
+        }
+        point -= weight as u64;
+    }
+    unreachable!("the point is below the total weight")
+}
+
+/// Find the persona by name, or the first one if no name is given.
+pub fn select_persona<'a>(
+    personas: &'a [Persona],
+    name: Option<&str>,
+) -> Result<&'a Persona, Box<dyn Error>> {
+    match name {
+        Some(name) => personas
+            .iter()
+            .find(|p| p.name == name)
+            .ok_or_else(|| format!("unknown persona {}", name).into()),
+        None => personas
+            .first()
+            .ok_or_else(|| "no persona is configured".into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_validate_personas() {
+        let personas = vec![Persona::new("a", "a.handle"), Persona::new("b", "b.handle")];
+        assert!(validate_personas(&personas).is_ok());
+        assert!(validate_personas(&[]).is_err());
+
+        let personas = vec![Persona::new("a", "a.handle"), Persona::new("a", "b.handle")];
+        assert!(validate_personas(&personas).is_err());
+
+        let personas = vec![Persona::new("a", "a.handle"), Persona::new("b", "a.handle")];
+        assert!(validate_personas(&personas).is_err());
+
+        let mut persona = Persona::new("a", "a.handle");
+        persona.variants = vec![variant("control", 0), variant("control", 1)];
+        assert!(validate_personas(&[persona.clone()]).is_err());
+        persona.variants[1].name = "treatment".to_string();
+        assert!(validate_personas(&[persona.clone()]).is_ok());
+        persona.variants[1].weight = 0;
+        assert!(validate_personas(&[persona]).is_err());
+    }
+
+    fn variant(name: &str, weight: u32) -> Variant {
+        Variant {
+            name: name.to_string(),
+            weight,
+            ..Persona::new("test", "test.handle").variants().remove(0)
+        }
+    }
+
+    #[test]
+    fn test_variants() {
+        let mut persona = Persona::new("zh", "zh.handle");
+        let variants = persona.variants();
+        assert_eq!(variants.len(), 1);
+        assert_eq!(variants[0].name, DEFAULT_VARIANT);
+        assert_eq!(variants[0].prompt_id(), "builtin");
+
+        persona.prompt_file = Some(PathBuf::from("data/prompt_en.txt"));
+        assert_eq!(persona.variants()[0].prompt_id(), "prompt_en");
+        persona.prompt_id = Some("en-2".to_string());
+        assert_eq!(persona.variants()[0].prompt_id(), "en-2");
+
+        persona.variants = vec![variant("a", 1), variant("b", 3)];
+        assert_eq!(persona.variants(), persona.variants);
+    }
+
+    #[test]
+    fn test_assign_variant() {
+        let summon = |i: usize| format!("at://did:plc:test/app.bsky.feed.post/{}", i);
+        assert_eq!(
+            assign_variant(&[1, 3], &summon(0)),
+            assign_variant(&[1, 3], &summon(0))
+        );
+        assert_eq!(assign_variant(&[0, 1, 0], &summon(0)), 1);
+        assert_eq!(assign_variant(&[], &summon(0)), 0);
+
+        let mut counts = [0; 2];
+        for i in 0..1000 {
+            counts[assign_variant(&[1, 3], &summon(i))] += 1;
+        }
+        // about a quarter of the summons get the first variant
+        assert!((150..350).contains(&counts[0]), "{:?}", counts);
+    }
+
+    #[test]
+    fn test_select_persona() {
+        let personas = vec![
+            Persona::new("zh", "zh.handle"),
+            Persona::new("en", "en.handle"),
+        ];
+        assert_eq!(select_persona(&personas, None).unwrap().name, "zh");
+        assert_eq!(select_persona(&personas, Some("en")).unwrap().name, "en");
+        assert!(select_persona(&personas, Some("fr")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_load_prompt() {
+        let mut persona = variant("zh", 1);
+        assert!(persona.load_prompt().await.unwrap().contains("歪楼"));
+
+        persona.prompt_file = Some(PathBuf::from("data/prompt_en.txt"));
+        assert!(persona.load_prompt().await.unwrap().contains("sidetrack"));
+
+        persona.detector.explain = true;
+        let prompt = persona.load_prompt().await.unwrap();
+        assert!(prompt.contains("sidetrack") && prompt.contains("REASON:"));
+
+        persona.detector.max_derailments = 3;
+        let prompt = persona.load_prompt().await.unwrap();
+        assert!(prompt.contains("at most 3 of them"));
+
+        persona.detector.find_rescuer = true;
+        assert!(persona.load_prompt().await.unwrap().contains("RESCUER:"));
+
+        assert!(persona.load_prompt().await.unwrap().contains("[OP]"));
+        assert!(!persona
+            .load_prompt()
+            .await
+            .unwrap()
+            .contains("never counts"));
+        persona.detector.spare_op = true;
+        assert!(persona
+            .load_prompt()
+            .await
+            .unwrap()
+            .contains("never counts"));
+
+        persona.prompt_file = Some(PathBuf::from("data/missing.txt"));
+        assert!(persona.load_prompt().await.is_err());
+    }
+
+    #[test]
+    fn test_allowlist() {
+        let did = Did::from_str("did:plc:friend").unwrap();
+        let stranger = Did::from_str("did:plc:stranger").unwrap();
+        let mut persona = Persona::new("test", "bot.handle");
+        assert!(persona.allows(&stranger, "stranger.handle"));
+
+        persona.allowlist = vec!["Friend.Handle".to_string(), "did:plc:friend".to_string()];
+        assert!(persona.allows(&did, "renamed.handle"));
+        assert!(persona.allows(&stranger, "friend.handle"));
+        assert!(!persona.allows(&stranger, "stranger.handle"));
+    }
+}
@@ -2,12 +2,13 @@ use atrium_api::app::bsky::embed::record::ViewRecordRefs;
 use atrium_api::app::bsky::feed::defs::{PostView, ThreadViewPost};
 use atrium_api::app::bsky::feed::defs::{PostViewEmbedRefs, ThreadViewPostParentRefs};
 use atrium_api::app::bsky::feed::post::RecordData;
-use atrium_api::types::string::{Cid, Did};
+use atrium_api::types::string::{Cid, Datetime, Did};
 use atrium_api::types::{TryFromUnknown, Union, Unknown};
-use log::debug;
+use chrono::{DateTime, Utc};
 use std::cell::RefCell;
 use std::collections::VecDeque;
 use std::rc::Rc;
+use tracing::debug;
 use url::Url;
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -78,6 +79,14 @@ pub struct Post {
     pub text: String,
     pub uri: String,
     pub idx: u32,
+    /// whether the post is by the author of the root post
+    pub by_op: bool,
+    /// whether the post is quoted by the root post rather than a reply in the thread
+    pub quoted: bool,
+    /// when the post was made, as told by the client of its author
+    pub created_at: Option<DateTime<Utc>>,
+    /// when the post was indexed by the app view
+    pub indexed_at: Option<DateTime<Utc>>,
 }
 
 impl Post {
@@ -96,6 +105,31 @@ impl Post {
             text: text.into(),
             uri: uri.into(),
             idx,
+            by_op: false,
+            quoted: false,
+            created_at: None,
+            indexed_at: None,
+        }
+    }
+
+    pub fn with_times(
+        self,
+        created_at: Option<DateTime<Utc>>,
+        indexed_at: Option<DateTime<Utc>>,
+    ) -> Self {
+        Self {
+            created_at,
+            indexed_at,
+            ..self
+        }
+    }
+
+    /// When the post was made. The client of the author may be off, so it is no later than the
+    /// post was indexed.
+    pub fn posted_at(&self) -> Option<DateTime<Utc>> {
+        match (self.created_at, self.indexed_at) {
+            (Some(created_at), Some(indexed_at)) => Some(created_at.min(indexed_at)),
+            (created_at, indexed_at) => created_at.or(indexed_at),
         }
     }
 
@@ -105,11 +139,7 @@ impl Post {
 }
 
 pub fn parse_record_from_unknown(unknown: &Unknown) -> Option<RecordData> {
-    if let Ok(record) = TryFromUnknown::try_from_unknown(unknown.clone()) {
-        Some(record)
-    } else {
-        None
-    }
+    TryFromUnknown::try_from_unknown(unknown.clone()).ok()
 }
 
 pub fn parse_post_text(post: &PostView) -> String {
@@ -124,7 +154,15 @@ pub fn parse_post_uri(post: &PostView) -> String {
     post.uri.clone()
 }
 
-pub fn get_parent<'a>(thread: &'a ThreadViewPost) -> Option<&'a ThreadViewPost> {
+fn to_utc(datetime: &Datetime) -> DateTime<Utc> {
+    datetime.as_ref().with_timezone(&Utc)
+}
+
+pub fn parse_post_created_at(post: &PostView) -> Option<DateTime<Utc>> {
+    parse_record_from_unknown(&post.record).map(|record| to_utc(&record.created_at))
+}
+
+pub fn get_parent(thread: &ThreadViewPost) -> Option<&ThreadViewPost> {
     if let Some(Union::Refs(ThreadViewPostParentRefs::ThreadViewPost(k))) = &thread.parent {
         Some(k)
     } else {
@@ -136,14 +174,20 @@ pub fn parse_embedded(post: &Option<Union<PostViewEmbedRefs>>) -> Option<Post> {
     if let &Some(Union::Refs(PostViewEmbedRefs::AppBskyEmbedRecordView(ref box_view))) = post {
         if let &Union::Refs(ViewRecordRefs::ViewRecord(ref box_record)) = &box_view.record {
             if let Some(record) = parse_record_from_unknown(&box_record.value) {
-                return Some(Post::new(
-                    box_record.cid.clone(),
-                    box_record.author.did.clone(),
-                    box_record.author.handle.as_str(),
-                    record.text,
-                    box_record.uri.as_str(),
-                    0,
-                ));
+                return Some(
+                    Post::new(
+                        box_record.cid.clone(),
+                        box_record.author.did.clone(),
+                        box_record.author.handle.as_str(),
+                        record.text,
+                        box_record.uri.as_str(),
+                        0,
+                    )
+                    .with_times(
+                        Some(to_utc(&record.created_at)),
+                        Some(to_utc(&box_record.indexed_at)),
+                    ),
+                );
             }
         }
     }
@@ -175,15 +219,20 @@ impl From<&ThreadViewPost> for FlattenedThread {
                 parse_post_text(post),
                 parse_post_uri(post),
                 0,
-            );
+            )
+            .with_times(parse_post_created_at(post), Some(to_utc(&post.indexed_at)));
             // ignore non text posts
-            if post.text.len() > 0 {
+            if !post.text.is_empty() {
                 result.push_front(Rc::new(RefCell::from(post)));
             }
             entrance.get_or_insert_with(|| result.front().unwrap().clone());
             if cur.parent.is_none() {
-                root = result.front().map(|p| p.clone());
+                root = result.front().cloned();
                 if let Some(post) = parse_embedded(&cur.post.embed) {
+                    let post = Post {
+                        quoted: true,
+                        ..post
+                    };
                     result.push_front(Rc::new(RefCell::from(post)));
                 }
                 break;
@@ -192,12 +241,13 @@ impl From<&ThreadViewPost> for FlattenedThread {
             }
         }
 
-        // renumber the posts
-        let mut idx: u32 = 1;
-        for p in result.iter_mut() {
-            p.borrow_mut().idx = idx;
+        // renumber the posts, and mark those by the original poster
+        let op = root.as_ref().map(|root| root.borrow().did.clone());
+        for (idx, p) in result.iter_mut().enumerate() {
+            let by_op = op.as_ref() == Some(&p.borrow().did);
+            p.borrow_mut().idx = idx as u32 + 1;
+            p.borrow_mut().by_op = by_op;
             debug!("{:?} {}", p, p.borrow().get_share_uri());
-            idx += 1;
         }
 
         Self {
@@ -208,16 +258,56 @@ impl From<&ThreadViewPost> for FlattenedThread {
     }
 }
 
+/// How many posts at the head of the thread make up its original topic, i.e. the root and the
+/// post quoted by the root if any.
+pub fn topic_len(posts: &VecDeque<Post>) -> usize {
+    posts
+        .iter()
+        .position(|p| !p.quoted)
+        .map_or(posts.len(), |root| root + 1)
+}
+
+/// Drop the posts created after the summon, which the summoner could not have seen. Returns how
+/// many are dropped.
+pub fn drop_posts_after(posts: &mut VecDeque<Post>, summoned_at: DateTime<Utc>) -> usize {
+    let len = posts.len();
+    posts.retain(|p| {
+        p.created_at
+            .is_none_or(|created_at| created_at <= summoned_at)
+    });
+    len - posts.len()
+}
+
 impl From<&FlattenedThread> for VecDeque<Post> {
     fn from(value: &FlattenedThread) -> Self {
-        VecDeque::<Post>::from_iter(
-            value.posts
-                .iter()
-                .map(|p| p.borrow().clone()),
-        )
+        VecDeque::<Post>::from_iter(value.posts.iter().map(|p| p.borrow().clone()))
     }
 }
 
+/// A post of a test thread, by an author whose DID is made of the handle.
+#[cfg(test)]
+pub(crate) fn test_post(idx: u32, handle: &str, text: &str) -> Post {
+    use std::str::FromStr;
+    Post::new(
+        Cid::from_str("bafyreihvgtbjqmyo2ocpfic3rgjtvepbopcfhsqwxynl2shc4cww3nnjly").unwrap(),
+        Did::new(format!("did:plc:{}", handle)).unwrap(),
+        handle,
+        text,
+        format!("at://did:plc:test/app.bsky.feed.post/{}", idx),
+        idx,
+    )
+}
+
+/// A test thread of the texts by the same author, numbered from 1.
+#[cfg(test)]
+pub(crate) fn test_thread(texts: &[&str]) -> VecDeque<Post> {
+    texts
+        .iter()
+        .zip(1..)
+        .map(|(text, idx)| test_post(idx, "test.handle", text))
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use std::str::FromStr;
@@ -325,17 +415,61 @@ mod tests {
             flattened.root.borrow().uri,
             "at://did:plc:xn5b64qpivpq55wumwf6wdjg/app.bsky.feed.post/3le7txyg4y22e"
         );
-        assert_eq!(
-            flattened.root.borrow().idx,
-            2
-        );
+        assert_eq!(flattened.root.borrow().idx, 2);
         assert_eq!(
             flattened.entrance.borrow().uri,
             "at://did:plc:xn5b64qpivpq55wumwf6wdjg/app.bsky.feed.post/3leb44umzuc2l"
         );
+        assert_eq!(flattened.entrance.borrow().idx, 13);
+        assert!(flattened.root.borrow().by_op);
+        assert!(flattened.entrance.borrow().by_op);
+        // the post quoted by the root is by someone else
+        assert!(!flattened.posts[0].borrow().by_op);
+        assert!(flattened.posts[0].borrow().quoted);
+        assert_eq!(topic_len(&VecDeque::from(&flattened)), 2);
+
+        let root = flattened.root.borrow();
         assert_eq!(
-            flattened.entrance.borrow().idx,
-            13
+            root.created_at,
+            Some("2024-12-26T16:03:15.800Z".parse().unwrap())
         );
+        assert!(root.indexed_at > root.created_at);
+        assert_eq!(root.posted_at(), root.created_at);
+        let posted_at: Vec<_> = flattened
+            .posts
+            .iter()
+            .map(|p| p.borrow().posted_at().unwrap())
+            .collect();
+        assert!(posted_at.is_sorted());
+        // the thread ends at the summon, so no post in it is made after the summon
+        assert!(Rc::ptr_eq(
+            flattened.posts.back().unwrap(),
+            &flattened.entrance
+        ));
+    }
+
+    #[test]
+    fn test_topic_len_without_quote() {
+        let thread = load_test_thread(RootPostThread);
+        let flattened = FlattenedThread::from(&thread);
+        assert!(parse_embedded(&thread.post.embed).is_none());
+        assert_eq!(flattened.root.borrow().idx, 1);
+        assert_eq!(topic_len(&VecDeque::from(&flattened)), 1);
+        assert_eq!(topic_len(&VecDeque::new()), 0);
+    }
+
+    #[test]
+    fn test_drop_posts_after() {
+        let thread = load_test_thread(LeafPostThread);
+        let flattened = FlattenedThread::from(&thread);
+        let mut posts = VecDeque::from(&flattened);
+        let summoned_at = flattened.entrance.borrow().created_at.unwrap();
+        assert_eq!(drop_posts_after(&mut posts, summoned_at), 0);
+        let summoned_at = posts[10].created_at.unwrap();
+        assert_eq!(drop_posts_after(&mut posts, summoned_at), 2);
+        assert_eq!(posts.len(), 11);
+        // posts with no creation time are kept
+        posts.push_back(test_post(20, "late.handle", "no time"));
+        assert_eq!(drop_posts_after(&mut posts, summoned_at), 0);
     }
 }
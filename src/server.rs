@@ -0,0 +1,133 @@
+use crate::metrics::METRICS;
+use async_trait::async_trait;
+use axum::extract::State;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::Router;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tracing::{error, info, warn};
+
+/// a slow dependency should not hang the probe of the supervisor
+const READY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Tells whether the service is ready to take work.
+#[async_trait]
+pub trait Probe: Send + Sync {
+    /// The problems found, if any.
+    async fn ready(&self) -> Result<(), Vec<String>>;
+}
+
+pub fn router(probe: Arc<dyn Probe>) -> Router {
+    Router::new()
+        .route("/metrics", get(metrics))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .with_state(probe)
+}
+
+async fn metrics() -> impl IntoResponse {
+    (
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        METRICS.render(),
+    )
+}
+
+/// Answering at all means the process is alive and the event loop is not blocked.
+async fn healthz() -> &'static str {
+    "ok\n"
+}
+
+async fn readyz(State(probe): State<Arc<dyn Probe>>) -> (StatusCode, String) {
+    let problems = match tokio::time::timeout(READY_TIMEOUT, probe.ready()).await {
+        Ok(Ok(())) => return (StatusCode::OK, "ready\n".to_string()),
+        Ok(Err(problems)) => problems,
+        Err(_) => vec!["readiness check timed out".to_string()],
+    };
+    warn!("not ready: {}", problems.join("; "));
+    let mut body = String::new();
+    for problem in problems {
+        body.push_str(&problem);
+        body.push('\n');
+    }
+    (StatusCode::SERVICE_UNAVAILABLE, body)
+}
+
+/// Bind the address and serve the router in the background.
+pub async fn spawn(listen: SocketAddr, router: Router) -> std::io::Result<SocketAddr> {
+    let listener = TcpListener::bind(listen).await?;
+    let local_addr = listener.local_addr()?;
+    info!("serving http on {}", local_addr);
+    tokio::spawn(async move {
+        if let Err(err) = axum::serve(listener, router).await {
+            error!("http server stopped: {}", err);
+        }
+    });
+    Ok(local_addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FakeProbe(Vec<String>);
+
+    #[async_trait]
+    impl Probe for FakeProbe {
+        async fn ready(&self) -> Result<(), Vec<String>> {
+            if self.0.is_empty() {
+                Ok(())
+            } else {
+                Err(self.0.clone())
+            }
+        }
+    }
+
+    async fn serve(probe: FakeProbe) -> SocketAddr {
+        spawn("127.0.0.1:0".parse().unwrap(), router(Arc::new(probe)))
+            .await
+            .unwrap()
+    }
+
+    async fn get(addr: SocketAddr, path: &str) -> (u16, String) {
+        let response = reqwest::get(format!("http://{}{}", addr, path))
+            .await
+            .unwrap();
+        let status = response.status().as_u16();
+        (status, response.text().await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_metrics_endpoint() {
+        METRICS.checks.inc();
+        let addr = serve(FakeProbe(vec![])).await;
+        let (status, body) = get(addr, "/metrics").await;
+        assert_eq!(status, 200);
+        assert!(body.contains("sidetracker_checks_total"));
+    }
+
+    #[tokio::test]
+    async fn test_health_endpoints() {
+        let addr = serve(FakeProbe(vec![])).await;
+        assert_eq!(get(addr, "/healthz").await, (200, "ok\n".to_string()));
+        assert_eq!(get(addr, "/readyz").await, (200, "ready\n".to_string()));
+
+        let addr = serve(FakeProbe(vec![
+            "bot: no successful poll yet".to_string(),
+            "detector: unreachable".to_string(),
+        ]))
+        .await;
+        assert_eq!(get(addr, "/healthz").await.0, 200);
+        assert_eq!(
+            get(addr, "/readyz").await,
+            (
+                503,
+                "bot: no successful poll yet\ndetector: unreachable\n".to_string()
+            )
+        );
+    }
+}
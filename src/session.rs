@@ -1,9 +1,169 @@
+use crate::crypto::{Envelope, SecretKey};
 use crate::util;
+use async_trait::async_trait;
 use atrium_api::agent::store::{MemorySessionStore, SessionStore};
 use atrium_api::agent::Session;
-use std::path::PathBuf;
+use atrium_api::com::atproto::server::refresh_session;
+use atrium_xrpc::http::header::{AUTHORIZATION, CONTENT_TYPE};
+use atrium_xrpc::http::{Request, Response, StatusCode};
+use atrium_xrpc::{HttpClient, XrpcClient};
+use std::error::Error;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use tracing::{error, info};
+
+/// The directory holding the session files, `$XDG_STATE_HOME/rust-sidetracker-bot`, which falls
+/// back to `~/.local/state/rust-sidetracker-bot`.
+pub fn state_dir() -> PathBuf {
+    util::xdg_app_dir("XDG_STATE_HOME", ".local/state")
+}
+
+/// The default session file of an account, named after its DID or handle so that sessions of
+/// different accounts don't clobber each other.
+pub fn default_session_file(identifier: &str) -> PathBuf {
+    let name: String = identifier
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    state_dir().join(format!("{}.json", name))
+}
+
+/// The encrypted counterpart of a plaintext session file.
+pub fn encrypted_session_file(path: impl AsRef<Path>) -> PathBuf {
+    with_suffix(path, ".enc")
+}
+
+fn with_suffix(path: impl AsRef<Path>, suffix: &str) -> PathBuf {
+    let mut path = OsString::from(path.as_ref().as_os_str());
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Serializes session refreshing among processes sharing a session file. Refresh tokens are
+/// rotated on use, so two processes refreshing with the same token would invalidate each other.
+/// Clones share the lock, so it can be taken again while held by any of them, as the file lock
+/// isn't reentrant.
+#[derive(Clone)]
+pub struct SessionLock {
+    path: PathBuf,
+    held: Arc<tokio::sync::Mutex<Weak<util::FileLock>>>,
+    // taken before refreshing the session, released once the rotated tokens are written
+    refreshing: Arc<Mutex<Option<SessionLockGuard>>>,
+}
+
+/// Holds the [`SessionLock`] until dropped, along with the other guards of the lock.
+pub type SessionLockGuard = Arc<util::FileLock>;
+
+impl SessionLock {
+    pub fn new(session_file: impl AsRef<Path>) -> Self {
+        Self {
+            path: with_suffix(session_file, ".lock"),
+            held: Default::default(),
+            refreshing: Default::default(),
+        }
+    }
+
+    pub async fn acquire(&self) -> Result<SessionLockGuard, Box<dyn Error>> {
+        let mut held = self.held.lock().await;
+        if let Some(guard) = held.upgrade() {
+            return Ok(guard);
+        }
+        let lock = util::lock_file(&self.path)
+            .await
+            .map_err(|err| format!("failed to lock {}: {}", self.path.display(), err))?;
+        let guard = Arc::new(lock);
+        *held = Arc::downgrade(&guard);
+        Ok(guard)
+    }
+
+    fn hold_until_written(&self, guard: SessionLockGuard) {
+        *self.refreshing.lock().unwrap() = Some(guard);
+    }
 
-pub const SESSION_FILE: &str = "session.json";
+    fn written(&self) {
+        self.refreshing.lock().unwrap().take();
+    }
+}
+
+/// Wraps an XRPC client to hold the [`SessionLock`] across refreshing the session. The lock is
+/// taken before the refresh request, and released by the [`ChainedSessionStore`] sharing it once
+/// the rotated tokens are written. If another process has refreshed the session in the meantime,
+/// the tokens it saved are returned instead of refreshing with the stale refresh token.
+pub struct RefreshLockingClient<T> {
+    inner: T,
+    lock: Option<(SessionLock, Arc<dyn ChainableSessionStore>)>,
+}
+
+impl<T> RefreshLockingClient<T> {
+    pub fn new(inner: T) -> Self {
+        Self { inner, lock: None }
+    }
+
+    /// Refresh under the lock, re-reading the session from the store of the session file.
+    pub fn with_lock(mut self, lock: SessionLock, store: Arc<dyn ChainableSessionStore>) -> Self {
+        self.lock = Some((lock, store));
+        self
+    }
+}
+
+type HttpResult = Result<Response<Vec<u8>>, Box<dyn Error + Send + Sync + 'static>>;
+
+impl<T: HttpClient + Send + Sync> HttpClient for RefreshLockingClient<T> {
+    async fn send_http(&self, request: Request<Vec<u8>>) -> HttpResult {
+        let Some((lock, store)) = &self.lock else {
+            return self.inner.send_http(request).await;
+        };
+        if request.uri().path() != format!("/xrpc/{}", refresh_session::NSID) {
+            return self.inner.send_http(request).await;
+        }
+        let guard = lock.acquire().await.map_err(|err| err.to_string())?;
+        let refresh_jwt = request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        let response = match store.get_session().await {
+            Some(session) if Some(session.refresh_jwt.as_str()) != refresh_jwt => {
+                info!("the session has been refreshed by another process");
+                refreshed_response(&session)
+            }
+            _ => self.inner.send_http(request).await,
+        };
+        // the agent writes the session after refreshing, even when it fails
+        lock.hold_until_written(guard);
+        response
+    }
+}
+
+impl<T: XrpcClient + Send + Sync> XrpcClient for RefreshLockingClient<T> {
+    fn base_uri(&self) -> String {
+        self.inner.base_uri()
+    }
+}
+
+/// The response refreshSession would give for the session.
+fn refreshed_response(session: &Session) -> HttpResult {
+    let output = refresh_session::OutputData {
+        access_jwt: session.access_jwt.clone(),
+        active: session.active,
+        did: session.did.clone(),
+        did_doc: session.did_doc.clone(),
+        handle: session.handle.clone(),
+        refresh_jwt: session.refresh_jwt.clone(),
+        status: session.status.clone(),
+    };
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(&output)?)?)
+}
 
 pub struct LocalFileSessionStore {
     path: PathBuf,
@@ -15,12 +175,6 @@ impl LocalFileSessionStore {
     }
 }
 
-impl Default for LocalFileSessionStore {
-    fn default() -> Self {
-        LocalFileSessionStore::new(SESSION_FILE)
-    }
-}
-
 impl SessionStore for LocalFileSessionStore {
     async fn get_session(&self) -> Option<Session> {
         let Ok(session) = util::load_from_file(&self.path).await else {
@@ -38,91 +192,298 @@ impl SessionStore for LocalFileSessionStore {
     }
 }
 
-pub enum ChainableSessionStore {
-    LocalFileSessionStore(LocalFileSessionStore),
-    MemorySessionStore(MemorySessionStore),
+/// Stores the session in a file encrypted with AES-256-GCM, using a key derived from a secret
+/// with Argon2.
+pub struct EncryptedFileSessionStore {
+    path: PathBuf,
+    key: SecretKey,
+    plaintext_path: Option<PathBuf>,
 }
 
-impl ChainableSessionStore {
-    pub fn memory() -> Self {
-        Self::MemorySessionStore(MemorySessionStore::default())
+impl EncryptedFileSessionStore {
+    pub fn new(path: impl Into<PathBuf>, key: SecretKey) -> Self {
+        Self {
+            path: path.into(),
+            key,
+            plaintext_path: None,
+        }
     }
-    #[allow(unused)]
-    pub fn local_file(path: impl Into<PathBuf>) -> Self {
-        Self::LocalFileSessionStore(LocalFileSessionStore::new(path))
+
+    /// Import the session from a plaintext session file when the encrypted file doesn't exist
+    /// yet. The plaintext file is removed once it has been migrated.
+    pub fn migrate_from(mut self, plaintext_path: impl Into<PathBuf>) -> Self {
+        self.plaintext_path = Some(plaintext_path.into());
+        self
     }
 
-    pub fn local_file_default() -> Self {
-        Self::LocalFileSessionStore(LocalFileSessionStore::default())
+    /// Read the session, failing if the file exists but can't be decrypted with the key, e.g.
+    /// when the key is wrong.
+    pub async fn load(&self) -> Result<Option<Session>, Box<dyn Error>> {
+        if !util::is_file_exists(&self.path).await {
+            return self.migrate().await;
+        }
+        let envelope: Envelope = util::load_from_file(&self.path).await?;
+        let plaintext = envelope.open(&self.key).map_err(|err| {
+            format!(
+                "cannot decrypt session file {}: {}",
+                self.path.display(),
+                err
+            )
+        })?;
+        Ok(Some(serde_json::from_slice(&plaintext)?))
     }
 
-    #[allow(unused)]
-    pub fn as_memory(&self) -> Option<&MemorySessionStore> {
-        match self {
-            &ChainableSessionStore::LocalFileSessionStore(_) => None,
-            &ChainableSessionStore::MemorySessionStore(ref s) => Some(s),
+    pub async fn save(&self, session: &Session) -> Result<(), Box<dyn Error>> {
+        let envelope = Envelope::seal(&self.key, &serde_json::to_vec(session)?)?;
+        util::dump_to_private_file(&self.path, &envelope).await
+    }
+
+    async fn migrate(&self) -> Result<Option<Session>, Box<dyn Error>> {
+        let Some(ref plaintext_path) = self.plaintext_path else {
+            return Ok(None);
+        };
+        if !util::is_file_exists(plaintext_path).await {
+            return Ok(None);
         }
+        let session: Session = util::load_from_file(plaintext_path).await?;
+        self.save(&session).await?;
+        util::remove_file(plaintext_path).await?;
+        info!(
+            "migrated session file {} to encrypted file {}",
+            plaintext_path.display(),
+            self.path.display()
+        );
+        Ok(Some(session))
     }
+}
 
-    #[allow(unused)]
-    pub fn as_local_file(&self) -> Option<&LocalFileSessionStore> {
-        match self {
-            &ChainableSessionStore::LocalFileSessionStore(ref s) => Some(s),
-            &ChainableSessionStore::MemorySessionStore(_) => None,
+impl SessionStore for EncryptedFileSessionStore {
+    async fn get_session(&self) -> Option<Session> {
+        match self.load().await {
+            Ok(session) => session,
+            Err(err) => {
+                error!("failed to load encrypted session: {}", err);
+                None
+            }
         }
     }
+
+    async fn set_session(&self, session: Session) {
+        if let Err(err) = self.save(&session).await {
+            error!("failed to save encrypted session: {}", err);
+        }
+    }
+
+    async fn clear_session(&self) {
+        let _ = util::remove_file(&self.path).await;
+    }
+}
+
+/// Object safe counterpart of [`SessionStore`], so that stores of different types can be chained
+/// together in a [`ChainedSessionStore`].
+#[async_trait]
+pub trait ChainableSessionStore: Send + Sync {
+    async fn get_session(&self) -> Option<Session>;
+    async fn set_session(&self, session: Session);
+    async fn clear_session(&self);
+}
+
+#[async_trait]
+impl ChainableSessionStore for LocalFileSessionStore {
+    async fn get_session(&self) -> Option<Session> {
+        SessionStore::get_session(self).await
+    }
+
+    async fn set_session(&self, session: Session) {
+        SessionStore::set_session(self, session).await
+    }
+
+    async fn clear_session(&self) {
+        SessionStore::clear_session(self).await
+    }
+}
+
+#[async_trait]
+impl ChainableSessionStore for MemorySessionStore {
+    async fn get_session(&self) -> Option<Session> {
+        SessionStore::get_session(self).await
+    }
+
+    async fn set_session(&self, session: Session) {
+        SessionStore::set_session(self, session).await
+    }
+
+    async fn clear_session(&self) {
+        SessionStore::clear_session(self).await
+    }
+}
+
+#[async_trait]
+impl ChainableSessionStore for EncryptedFileSessionStore {
+    async fn get_session(&self) -> Option<Session> {
+        SessionStore::get_session(self).await
+    }
+
+    async fn set_session(&self, session: Session) {
+        SessionStore::set_session(self, session).await
+    }
+
+    async fn clear_session(&self) {
+        SessionStore::clear_session(self).await
+    }
+}
+
+#[async_trait]
+impl<T: ChainableSessionStore + ?Sized> ChainableSessionStore for Arc<T> {
+    async fn get_session(&self) -> Option<Session> {
+        self.as_ref().get_session().await
+    }
+
+    async fn set_session(&self, session: Session) {
+        self.as_ref().set_session(session).await
+    }
+
+    async fn clear_session(&self) {
+        self.as_ref().clear_session().await
+    }
+}
+
+/// How a [`ChainedSessionStore`] propagates sessions between its stores.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum ChainPolicy {
+    /// Read from the first store having a session, write to and clear all stores.
+    #[default]
+    WriteThrough,
+    /// Same as [`ChainPolicy::WriteThrough`], and additionally backfill the stores before the one
+    /// that hits, so that later reads are served by the earlier stores.
+    ReadThrough,
 }
 
 pub struct ChainedSessionStore {
-    stores: Vec<ChainableSessionStore>,
+    stores: Vec<Box<dyn ChainableSessionStore>>,
+    policy: ChainPolicy,
+    session_lock: Option<SessionLock>,
 }
 
 impl ChainedSessionStore {
-    pub fn new(stores: Vec<ChainableSessionStore>) -> Self {
-        Self { stores }
+    pub fn builder() -> ChainedSessionStoreBuilder {
+        ChainedSessionStoreBuilder::default()
+    }
+
+    async fn lock(&self) -> Option<SessionLockGuard> {
+        match self.session_lock.as_ref()?.acquire().await {
+            Ok(guard) => Some(guard),
+            Err(err) => {
+                error!("writing the session without the lock: {}", err);
+                None
+            }
+        }
+    }
+
+    fn written(&self) {
+        if let Some(lock) = &self.session_lock {
+            lock.written();
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct ChainedSessionStoreBuilder {
+    stores: Vec<Box<dyn ChainableSessionStore>>,
+    policy: ChainPolicy,
+    session_lock: Option<SessionLock>,
+}
+
+impl ChainedSessionStoreBuilder {
+    /// Append a store to the chain. Stores added earlier take priority when reading.
+    pub fn store(mut self, store: impl ChainableSessionStore + 'static) -> Self {
+        self.stores.push(Box::new(store));
+        self
+    }
+
+    pub fn memory(self) -> Self {
+        self.store(MemorySessionStore::default())
+    }
+
+    #[allow(unused)]
+    pub fn local_file(self, path: impl Into<PathBuf>) -> Self {
+        self.store(LocalFileSessionStore::new(path))
+    }
+
+    #[allow(unused)]
+    pub fn policy(mut self, policy: ChainPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Hold the [`SessionLock`] while writing the session, and release it afterwards if it was
+    /// held for refreshing the session.
+    pub fn lock(mut self, lock: SessionLock) -> Self {
+        self.session_lock = Some(lock);
+        self
+    }
+
+    pub fn build(self) -> ChainedSessionStore {
+        ChainedSessionStore {
+            stores: self.stores,
+            policy: self.policy,
+            session_lock: self.session_lock,
+        }
     }
 }
 
 impl SessionStore for ChainedSessionStore {
     async fn get_session(&self) -> Option<Session> {
-        for store in &self.stores {
-            let session = match store {
-                ChainableSessionStore::LocalFileSessionStore(s) => s.get_session().await,
-                ChainableSessionStore::MemorySessionStore(s) => s.get_session().await,
+        for (i, store) in self.stores.iter().enumerate() {
+            let Some(session) = store.get_session().await else {
+                continue;
             };
-            if session.is_some() {
-                return session;
+            if self.policy == ChainPolicy::ReadThrough {
+                for earlier in &self.stores[..i] {
+                    earlier.set_session(session.clone()).await;
+                }
             }
+            return Some(session);
         }
         None
     }
 
     async fn set_session(&self, session: Session) {
+        let _guard = self.lock().await;
         for store in &self.stores {
-            match store {
-                ChainableSessionStore::LocalFileSessionStore(s) => {
-                    s.set_session(session.clone()).await
-                }
-                ChainableSessionStore::MemorySessionStore(s) => {
-                    s.set_session(session.clone()).await
-                }
-            };
+            store.set_session(session.clone()).await;
         }
+        self.written();
     }
 
     async fn clear_session(&self) {
+        let _guard = self.lock().await;
         for store in &self.stores {
-            match store {
-                ChainableSessionStore::LocalFileSessionStore(s) => s.clear_session().await,
-                ChainableSessionStore::MemorySessionStore(s) => s.clear_session().await,
-            };
+            store.clear_session().await;
         }
+        self.written();
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::*;
+    use super::{
+        default_session_file, encrypted_session_file, with_suffix, ChainPolicy,
+        ChainedSessionStore, EncryptedFileSessionStore, LocalFileSessionStore,
+        RefreshLockingClient, SessionLock,
+    };
+    use crate::crypto::SecretKey;
+    use crate::util;
+    use atrium_api::agent::store::{MemorySessionStore, SessionStore};
+    use atrium_api::agent::Session;
+    use atrium_api::com::atproto::server::refresh_session;
+    use atrium_xrpc::http::{Request, Response};
+    use atrium_xrpc::HttpClient;
+    use std::error::Error;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
 
     fn mock_session(handle: &str) -> Session {
         let session = r#"{
@@ -131,7 +492,7 @@ mod tests {
             "handle": "HANDLE_NAME",
             "refreshJwt": "test-saved-refresh-jwt"
         }"#
-            .replace("HANDLE_NAME", handle);
+        .replace("HANDLE_NAME", handle);
         serde_json::from_str::<Session>(session.as_str()).unwrap()
     }
 
@@ -154,25 +515,99 @@ mod tests {
         assert!(session3.is_none());
     }
 
+    #[test]
+    fn test_default_session_file() {
+        let path = default_session_file("did:plc:test_did");
+        assert_eq!(path.file_name().unwrap(), "did_plc_test_did.json");
+        assert!(path.parent().unwrap().ends_with("rust-sidetracker-bot"));
+
+        let path = default_session_file("../test.handle");
+        assert_eq!(path.file_name().unwrap(), ".._test.handle.json");
+    }
+
+    #[test]
+    fn test_encrypted_session_file() {
+        assert_eq!(
+            encrypted_session_file("/tmp/test.json"),
+            PathBuf::from("/tmp/test.json.enc")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_file_session_store() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("session.json.enc");
+        let store = EncryptedFileSessionStore::new(&path, SecretKey::from_passphrase("key"));
+        let session = create_test_session();
+        store.set_session(session.clone()).await;
+
+        let content = tokio::fs::read_to_string(&path).await.unwrap();
+        assert!(!content.contains("test-saved-access-jwt"));
+        assert_eq!(store.get_session().await.unwrap(), session);
+
+        store.clear_session().await;
+        assert!(store.get_session().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_file_session_store_wrong_key() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("session.json.enc");
+        let store = EncryptedFileSessionStore::new(&path, SecretKey::from_passphrase("key"));
+        store.set_session(create_test_session()).await;
+
+        let store = EncryptedFileSessionStore::new(&path, SecretKey::from_passphrase("wrong"));
+        let err = store.load().await.unwrap_err();
+        assert!(err.to_string().contains("cannot decrypt session file"));
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_file_session_store_migration() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let plaintext_path = tmp_dir.path().join("session.json");
+        let path = tmp_dir.path().join("session.json.enc");
+        let session = create_test_session();
+        LocalFileSessionStore::new(&plaintext_path)
+            .set_session(session.clone())
+            .await;
+
+        let store = EncryptedFileSessionStore::new(&path, SecretKey::from_passphrase("key"))
+            .migrate_from(&plaintext_path);
+        assert_eq!(store.get_session().await.unwrap(), session);
+        assert!(!plaintext_path.exists());
+        assert!(path.exists());
+        assert_eq!(store.get_session().await.unwrap(), session);
+    }
+
     struct TestSessionStoreWrapper {
         chained_session_store: ChainedSessionStore,
+        fs: Arc<LocalFileSessionStore>,
+        ms: Arc<MemorySessionStore>,
         #[allow(unused)]
         temp_session_file: tempfile::NamedTempFile,
     }
 
-    fn get_test_chained_session_store() -> TestSessionStoreWrapper {
+    fn get_test_chained_session_store_with_policy(policy: ChainPolicy) -> TestSessionStoreWrapper {
         let tmp = tempfile::NamedTempFile::new().unwrap();
-        let fs = ChainableSessionStore::local_file(tmp.path());
-        let ms = ChainableSessionStore::memory();
-        let chained = ChainedSessionStore {
-            stores: vec![fs, ms],
-        };
+        let fs = Arc::new(LocalFileSessionStore::new(tmp.path()));
+        let ms = Arc::new(MemorySessionStore::default());
+        let chained = ChainedSessionStore::builder()
+            .store(fs.clone())
+            .store(ms.clone())
+            .policy(policy)
+            .build();
         TestSessionStoreWrapper {
             chained_session_store: chained,
+            fs,
+            ms,
             temp_session_file: tmp,
         }
     }
 
+    fn get_test_chained_session_store() -> TestSessionStoreWrapper {
+        get_test_chained_session_store_with_policy(ChainPolicy::default())
+    }
+
     fn split_test_chained_session_store(
         store: &TestSessionStoreWrapper,
     ) -> (
@@ -180,21 +615,7 @@ mod tests {
         &LocalFileSessionStore,
         &MemorySessionStore,
     ) {
-        let fs = store
-            .chained_session_store
-            .stores
-            .get(0)
-            .unwrap()
-            .as_local_file()
-            .unwrap();
-        let ms = store
-            .chained_session_store
-            .stores
-            .get(1)
-            .unwrap()
-            .as_memory()
-            .unwrap();
-        (&store.chained_session_store, fs, ms)
+        (&store.chained_session_store, &store.fs, &store.ms)
     }
 
     #[tokio::test]
@@ -267,4 +688,137 @@ mod tests {
         assert!(session2.is_none());
         assert!(session3.is_none());
     }
+
+    #[tokio::test]
+    async fn test_write_through_does_not_backfill() {
+        let chained_store = get_test_chained_session_store();
+        let (chained_store, fs, ms) = split_test_chained_session_store(&chained_store);
+        let session = create_test_session();
+        ms.set_session(session.clone()).await;
+
+        assert_eq!(chained_store.get_session().await.unwrap(), session);
+        assert!(fs.get_session().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_read_through_backfills_earlier_stores() {
+        let chained_store = get_test_chained_session_store_with_policy(ChainPolicy::ReadThrough);
+        let (chained_store, fs, ms) = split_test_chained_session_store(&chained_store);
+        let session = create_test_session();
+        ms.set_session(session.clone()).await;
+
+        assert_eq!(chained_store.get_session().await.unwrap(), session);
+        assert_eq!(fs.get_session().await.unwrap(), session);
+    }
+
+    #[tokio::test]
+    async fn test_chained_store_writes_under_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session.json");
+        let lock = SessionLock::new(&path);
+        let store = Arc::new(
+            ChainedSessionStore::builder()
+                .local_file(&path)
+                .lock(lock.clone())
+                .build(),
+        );
+
+        // the lock is shared with its clones, e.g. with a login holding it
+        let held = lock.acquire().await.unwrap();
+        assert!(Arc::ptr_eq(&held, &lock.clone().acquire().await.unwrap()));
+        store.set_session(create_test_session()).await;
+        drop(held);
+
+        // another process holding the lock, e.g. while refreshing the session
+        let other = util::lock_file(with_suffix(&path, ".lock")).await.unwrap();
+        let writing = tokio::spawn({
+            let store = store.clone();
+            async move { store.clear_session().await }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!writing.is_finished());
+        assert!(store.get_session().await.is_some());
+        drop(other);
+        writing.await.unwrap();
+        assert!(store.get_session().await.is_none());
+    }
+
+    /// Answers refreshSession with new tokens, counting the calls.
+    #[derive(Clone, Default)]
+    struct MockRefreshClient {
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl HttpClient for MockRefreshClient {
+        async fn send_http(
+            &self,
+            _request: Request<Vec<u8>>,
+        ) -> Result<Response<Vec<u8>>, Box<dyn Error + Send + Sync + 'static>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            let body = r#"{
+                "accessJwt": "refreshed-access-jwt",
+                "did": "did:plc:test.handle",
+                "handle": "test.handle",
+                "refreshJwt": "refreshed-refresh-jwt"
+            }"#;
+            Ok(Response::builder().status(200).body(body.into())?)
+        }
+    }
+
+    fn refresh_request(refresh_jwt: &str) -> Request<Vec<u8>> {
+        Request::post("https://bsky.social/xrpc/com.atproto.server.refreshSession")
+            .header("authorization", format!("Bearer {}", refresh_jwt))
+            .body(Vec::new())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_refresh_by_two_stores_on_one_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session.json");
+        let session = create_test_session();
+        LocalFileSessionStore::new(&path)
+            .set_session(session.clone())
+            .await;
+        let inner = MockRefreshClient::default();
+        // two processes sharing the session file, each with its own lock and stores
+        let open = || {
+            let lock = SessionLock::new(&path);
+            let file_store = Arc::new(LocalFileSessionStore::new(&path));
+            let store = ChainedSessionStore::builder()
+                .store(file_store.clone())
+                .memory()
+                .lock(lock.clone())
+                .build();
+            let client = RefreshLockingClient::new(inner.clone()).with_lock(lock, file_store);
+            (store, client)
+        };
+        let (store_a, client_a) = open();
+        let (store_b, client_b) = open();
+
+        // both refresh with the same token, the first holds the lock until it has written
+        client_a
+            .send_http(refresh_request(&session.refresh_jwt))
+            .await
+            .unwrap();
+        let refreshing = tokio::spawn({
+            let refresh_jwt = session.refresh_jwt.clone();
+            async move { client_b.send_http(refresh_request(&refresh_jwt)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!refreshing.is_finished());
+        let mut refreshed = session.clone();
+        refreshed.access_jwt = "refreshed-access-jwt".into();
+        refreshed.refresh_jwt = "refreshed-refresh-jwt".into();
+        store_a.set_session(refreshed.clone()).await;
+
+        // the second is given the rotated tokens instead of refreshing with the stale token
+        let response = refreshing.await.unwrap().unwrap();
+        let output: refresh_session::OutputData = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(output.access_jwt, refreshed.access_jwt);
+        assert_eq!(output.refresh_jwt, refreshed.refresh_jwt);
+        assert_eq!(inner.calls.load(Ordering::SeqCst), 1);
+        store_b.set_session(refreshed.clone()).await;
+        assert_eq!(store_a.get_session().await.unwrap(), refreshed);
+    }
 }
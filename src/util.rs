@@ -1,14 +1,26 @@
-#[cfg(test)]
-use pretty_env_logger::env_logger;
-use std::path::Path;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
 use tokio::io::AsyncWriteExt;
 
+pub const APP_NAME: &str = env!("CARGO_PKG_NAME");
+
+/// The directory of this app under an XDG base directory, e.g. `$XDG_CONFIG_HOME/<app>`, falling
+/// back to the given directory under the home directory if the env var isn't an absolute path.
+pub fn xdg_app_dir(env_var: &str, home_fallback: &str) -> PathBuf {
+    let base = std::env::var_os(env_var)
+        .map(PathBuf::from)
+        .filter(|p| p.is_absolute())
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(home_fallback)))
+        .unwrap_or_default();
+    base.join(APP_NAME)
+}
+
 pub fn find_and_parse_first_integer(input: String) -> Option<u32> {
     let mut num_str = String::new();
     let mut found_number = false;
 
     for c in input.chars() {
-        if c.is_digit(10) {
+        if c.is_ascii_digit() {
             num_str.push(c);
             found_number = true;
         } else if found_number {
@@ -16,10 +28,18 @@ pub fn find_and_parse_first_integer(input: String) -> Option<u32> {
         }
     }
 
-    if let Ok(num) = num_str.parse::<u32>() {
-        Some(num)
-    } else {
-        None
+    num_str.parse::<u32>().ok()
+}
+
+/// A duration in its largest whole unit, e.g. `45s`, `3m`, `2h` or `5d`. Negative durations,
+/// from clients with clocks off, count as 0.
+pub fn format_duration(duration: chrono::Duration) -> String {
+    let secs = duration.num_seconds().max(0);
+    match secs {
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m", secs / 60),
+        3600..86400 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
     }
 }
 
@@ -32,6 +52,9 @@ pub fn ensure_tailing_slash(s: &str) -> String {
     s
 }
 
+/// Serialize data into a file only readable by the current user. The file is replaced
+/// atomically, so that concurrent readers never see a partially written file, and the parent
+/// directories are created if needed.
 pub async fn dump_to_private_file<T>(
     file_path: impl AsRef<Path>,
     data: &T,
@@ -39,19 +62,63 @@ pub async fn dump_to_private_file<T>(
 where
     T: ?Sized + serde::Serialize,
 {
+    let file_path = file_path.as_ref();
+    if let Some(parent) = file_path.parent() {
+        create_private_dir(parent).await?;
+    }
+    let mut tmp_path = file_path.as_os_str().to_owned();
+    tmp_path.push(format!(".{}.tmp", std::process::id()));
     let mut file = tokio::fs::OpenOptions::new()
         .write(true)
         .create(true)
         .truncate(true)
         .mode(0o600)
-        .open(file_path)
+        .open(&tmp_path)
         .await?;
     let json = serde_json::to_string_pretty(data)?;
     file.write_all(json.as_bytes()).await?;
     file.flush().await?;
+    file.sync_all().await?;
+    tokio::fs::rename(&tmp_path, file_path).await?;
     Ok(())
 }
 
+pub async fn create_private_dir(dir_path: impl AsRef<Path>) -> std::io::Result<()> {
+    if dir_path.as_ref().as_os_str().is_empty() || is_file_exists(dir_path.as_ref()).await {
+        return Ok(());
+    }
+    tokio::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir_path)
+        .await
+}
+
+/// An advisory lock on a file, released when dropped.
+pub struct FileLock {
+    _file: std::fs::File,
+}
+
+/// Take an exclusive advisory lock on the file, waiting for other processes holding it. The file
+/// is created if it doesn't exist.
+pub async fn lock_file(file_path: impl AsRef<Path>) -> std::io::Result<FileLock> {
+    let file_path = file_path.as_ref().to_owned();
+    if let Some(parent) = file_path.parent() {
+        create_private_dir(parent).await?;
+    }
+    tokio::task::spawn_blocking(move || {
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .open(file_path)?;
+        file.lock()?;
+        Ok(FileLock { _file: file })
+    })
+    .await?
+}
+
 pub async fn load_from_file<T>(file_path: impl AsRef<Path>) -> Result<T, Box<dyn std::error::Error>>
 where
     T: serde::de::DeserializeOwned,
@@ -75,9 +142,9 @@ pub async fn remove_file(file_path: impl AsRef<Path>) -> std::io::Result<()> {
 #[cfg(test)]
 #[allow(dead_code)]
 pub fn init_test_logger() {
-    let _ = env_logger::builder()
-        .is_test(true)
-        .filter_level(log::LevelFilter::Trace)
+    let _ = tracing_subscriber::fmt()
+        .with_test_writer()
+        .with_max_level(tracing::Level::TRACE)
         .try_init();
 }
 
@@ -150,13 +217,26 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_format_duration() {
+        use chrono::Duration;
+        assert_eq!(format_duration(Duration::seconds(45)), "45s");
+        assert_eq!(format_duration(Duration::seconds(200)), "3m");
+        assert_eq!(format_duration(Duration::hours(2)), "2h");
+        assert_eq!(
+            format_duration(Duration::days(5) + Duration::hours(23)),
+            "5d"
+        );
+        assert_eq!(format_duration(Duration::seconds(-30)), "0s");
+    }
+
     #[test]
     fn test_ensure_tailing_slash() {
         let s = "https://example.com";
-        assert_eq!(ensure_tailing_slash(&s), "https://example.com/");
+        assert_eq!(ensure_tailing_slash(s), "https://example.com/");
 
         let s = "https://example.com/";
-        assert_eq!(ensure_tailing_slash(&s), "https://example.com/");
+        assert_eq!(ensure_tailing_slash(s), "https://example.com/");
     }
 
     #[tokio::test]
@@ -177,6 +257,35 @@ mod tests {
         tmp_dir.close().unwrap();
     }
 
+    #[tokio::test]
+    async fn test_dump_to_private_file_creates_parents() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = tmp_dir.path().join("a").join("b").join("test.json");
+        dump_to_private_file(&file_path, &1).await.unwrap();
+        let loaded: i32 = load_from_file(&file_path).await.unwrap();
+        assert_eq!(loaded, 1);
+
+        let metadata = tokio::fs::metadata(file_path.parent().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o700);
+    }
+
+    #[tokio::test]
+    async fn test_lock_file() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = tmp_dir.path().join("test.lock");
+        let lock = lock_file(&file_path).await.unwrap();
+
+        let waiting = tokio::spawn(lock_file(file_path.clone()));
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(!waiting.is_finished());
+
+        drop(lock);
+        let lock = tokio::time::timeout(std::time::Duration::from_secs(5), waiting).await;
+        assert!(lock.unwrap().unwrap().is_ok());
+    }
+
     #[tokio::test]
     async fn test_file_exists_and_removal() {
         let tmp_dir = tempfile::tempdir().unwrap();